@@ -0,0 +1,379 @@
+use axum::{Json, extract::{Path, State}};
+use crate::api::{error::XrpcError, AdminAuth, QsQuery, XrpcJson};
+use crate::config::config;
+use crate::db::{self, LabelEventRow, OptOutRow, ReportFilter, ReportRow};
+use crate::domain::fortune::{random_fortune, Fortune};
+use crate::domain::labeling::{opt_in, opt_out, overwrite_fortune, restore_daily_fortune, revoke_fortune, OptedOut};
+use crate::state::AppState;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tracing;
+
+const LIST_LIMIT_DEFAULT: i64 = 50;
+const LIST_LIMIT_MAX: i64 = 100;
+
+/// A stored report as shown to admins.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportView {
+    id: i64,
+    subject: serde_json::Value,
+    subject_did: Option<String>,
+    reason_type: String,
+    reason: Option<String>,
+    reported_by: String,
+    created_at: String,
+    action: Option<String>,
+    resolution: Option<String>,
+    note: Option<String>,
+    resolved_at: Option<String>,
+}
+
+impl From<ReportRow> for ReportView {
+    fn from(row: ReportRow) -> Self {
+        Self {
+            id: row.id,
+            subject: serde_json::from_str(&row.subject).unwrap_or(serde_json::Value::Null),
+            subject_did: row.subject_did,
+            reason_type: row.reason_type,
+            reason: row.reason,
+            reported_by: row.reported_by,
+            created_at: row.created_at,
+            action: row.action,
+            resolution: row.resolution,
+            note: row.note,
+            resolved_at: row.resolved_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListReportsParams {
+    /// `open` (default): queued and not yet resolved. `resolved`: reviewed. `all`: every report received.
+    status: Option<String>,
+    reason_type: Option<String>,
+    subject: Option<String>,
+    reported_by: Option<String>,
+    cursor: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ListReportsOutput {
+    reports: Vec<ReportView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+pub async fn list_reports(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    QsQuery(params): QsQuery<ListReportsParams>,
+) -> Result<Json<ListReportsOutput>, XrpcError> {
+    let (resolved, queued_only) = match params.status.as_deref().unwrap_or("open") {
+        "open" => (Some(false), true),
+        "resolved" => (Some(true), false),
+        "all" => (None, false),
+        other => return Err(XrpcError::InvalidRequest(format!("Unknown status: {}", other))),
+    };
+    let filter = ReportFilter {
+        resolved,
+        queued_only,
+        reason_type: params.reason_type,
+        subject_did: params.subject,
+        reported_by: params.reported_by,
+    };
+    let limit = params.limit.unwrap_or(LIST_LIMIT_DEFAULT).clamp(1, LIST_LIMIT_MAX);
+
+    let rows = db::list_reports(&state.pool, &filter, params.cursor, limit).await?;
+    let cursor = if rows.len() as i64 == limit {
+        rows.last().map(|r| r.id.to_string())
+    } else {
+        None
+    };
+
+    Ok(Json(ListReportsOutput {
+        reports: rows.into_iter().map(ReportView::from).collect(),
+        cursor,
+    }))
+}
+
+pub async fn get_report(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ReportView>, XrpcError> {
+    let row = find_report(&state, &id).await?;
+    Ok(Json(row.into()))
+}
+
+/// What an admin decided to do about a report.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Resolution {
+    /// Force a fortune label on the subject.
+    Apply { val: String },
+    /// Negate the subject's current labels.
+    Negate,
+    /// Leave the labels alone.
+    Dismiss,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolveReportInput {
+    #[serde(flatten)]
+    resolution: Resolution,
+    note: Option<String>,
+}
+
+pub async fn resolve_report(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    XrpcJson(input): XrpcJson<ResolveReportInput>,
+) -> Result<Json<ReportView>, XrpcError> {
+    let row = find_report(&state, &id).await?;
+    if row.resolved_at.is_some() {
+        return Err(XrpcError::InvalidRequest(format!("Report {} is already resolved", row.id)));
+    }
+
+    // Work out everything up front, so that nothing can fail between claiming the report and acting on it
+    let (resolution, target) = match input.resolution {
+        Resolution::Apply { val } => {
+            let fortune = Fortune::from_str(&val)
+                .map_err(|_| XrpcError::InvalidRequest(format!("Unknown label value: {}", val)))?;
+            (format!("apply:{}", fortune), Some((subject_of(&row)?, Some(fortune))))
+        }
+        Resolution::Negate => ("negate".to_string(), Some((subject_of(&row)?, None))),
+        Resolution::Dismiss => ("dismiss".to_string(), None),
+    };
+
+    // Claim the report before touching labels, so two admins resolving it at once don't both act on it
+    let resolved_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    if !db::resolve_report(&mut *state.pool.acquire().await?, row.id, &resolution, input.note.as_deref(), &resolved_at).await? {
+        return Err(XrpcError::InvalidRequest(format!("Report {} is already resolved", row.id)));
+    }
+
+    if let Some((did, fortune)) = target {
+        let labeler_did = &config().labeler_did;
+        let applied = match fortune {
+            Some(fortune) => overwrite_fortune(did, fortune.as_str(), &state.pool, &state.keypair, labeler_did, &state.tx).await,
+            None => revoke_fortune(did, &state.pool, &state.keypair, labeler_did, &state.tx).await,
+        };
+        if let Err(e) = applied {
+            db::reopen_report(&mut *state.pool.acquire().await?, row.id, &resolved_at).await?;
+            if e.is::<OptedOut>() {
+                return Err(XrpcError::InvalidRequest(e.to_string()));
+            }
+            return Err(e.into());
+        }
+    }
+
+    tracing::info!(id = row.id, resolution, note = input.note, "Report resolved");
+
+    let row = find_report(&state, &row.id.to_string()).await?;
+    Ok(Json(row.into()))
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListOptOutsOutput {
+    opt_outs: Vec<OptOutRow>,
+}
+
+pub async fn list_opt_outs(
+    _: AdminAuth,
+    State(state): State<AppState>,
+) -> Result<Json<ListOptOutsOutput>, XrpcError> {
+    let opt_outs = db::list_opt_outs(&state.pool).await?;
+    Ok(Json(ListOptOutsOutput { opt_outs }))
+}
+
+/// Opts `did` out and revokes its labels.
+pub async fn add_opt_out(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+) -> Result<Json<ListOptOutsOutput>, XrpcError> {
+    let did = parse_did(&did)?;
+    opt_out(&did, "admin", &state.pool, &state.keypair, &config().labeler_did, &state.tx).await?;
+    list_opt_outs(AdminAuth, State(state)).await
+}
+
+/// Opts `did` back in and gives it today's fortune.
+pub async fn remove_opt_out(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+) -> Result<Json<ListOptOutsOutput>, XrpcError> {
+    let did = parse_did(&did)?;
+    opt_in(&did, &state.pool, &state.keypair, &config().labeler_did, &state.tx).await?;
+    list_opt_outs(AdminAuth, State(state)).await
+}
+
+/// A label value currently on an account.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentLabel {
+    val: String,
+    cts: String,
+    /// Set by an override rather than the daily draw.
+    fixed: bool,
+}
+
+/// One emitted label, as recorded in the event log.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelHistoryEntry {
+    seq: i64,
+    val: String,
+    neg: bool,
+    cts: String,
+    src: String,
+}
+
+impl From<LabelEventRow> for LabelHistoryEntry {
+    fn from(row: LabelEventRow) -> Self {
+        Self {
+            seq: row.seq,
+            val: row.val,
+            neg: row.neg != 0,
+            cts: row.cts,
+            src: row.src,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountLabelsOutput {
+    did: String,
+    opted_out: bool,
+    labels: Vec<CurrentLabel>,
+    history: Vec<LabelHistoryEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LabelHistoryParams {
+    cursor: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Current labels of `did` and every label ever emitted for it, newest first.
+pub async fn get_account_labels(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+    QsQuery(params): QsQuery<LabelHistoryParams>,
+) -> Result<Json<AccountLabelsOutput>, XrpcError> {
+    let did = parse_did(&did)?;
+    let limit = params.limit.unwrap_or(LIST_LIMIT_DEFAULT).clamp(1, LIST_LIMIT_MAX);
+    Ok(Json(account_labels(&state, did, params.cursor, limit).await?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetLabelInput {
+    val: String,
+}
+
+/// Forces `val` on `did` for today.
+pub async fn set_account_label(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+    XrpcJson(input): XrpcJson<SetLabelInput>,
+) -> Result<Json<AccountLabelsOutput>, XrpcError> {
+    let did = labelable_did(&state, &did).await?;
+    let fortune = Fortune::from_str(&input.val)
+        .map_err(|_| XrpcError::InvalidRequest(format!("Unknown label value: {}", input.val)))?;
+    overwrite_fortune(&did, fortune.as_str(), &state.pool, &state.keypair, &config().labeler_did, &state.tx).await?;
+    tracing::info!(did, %fortune, "Admin set fortune");
+    Ok(Json(account_labels(&state, did, None, LIST_LIMIT_DEFAULT).await?))
+}
+
+/// Forces a new random fortune on `did` for today.
+pub async fn reroll_account_label(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+) -> Result<Json<AccountLabelsOutput>, XrpcError> {
+    let did = labelable_did(&state, &did).await?;
+    let fortune = random_fortune();
+    overwrite_fortune(&did, fortune.as_str(), &state.pool, &state.keypair, &config().labeler_did, &state.tx).await?;
+    tracing::info!(did, %fortune, "Admin rerolled fortune");
+    Ok(Json(account_labels(&state, did, None, LIST_LIMIT_DEFAULT).await?))
+}
+
+/// Drops any override and goes back to the daily fortune of `did`.
+pub async fn restore_account_label(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+) -> Result<Json<AccountLabelsOutput>, XrpcError> {
+    let did = labelable_did(&state, &did).await?;
+    let fortune = restore_daily_fortune(&did, &state.pool, &state.keypair, &config().labeler_did, &state.tx).await?;
+    tracing::info!(did, %fortune, "Admin restored fortune");
+    Ok(Json(account_labels(&state, did, None, LIST_LIMIT_DEFAULT).await?))
+}
+
+/// Negates every label on `did`. The next batch labels it again unless it has opted out.
+pub async fn revoke_account_label(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(did): Path<String>,
+) -> Result<Json<AccountLabelsOutput>, XrpcError> {
+    let did = parse_did(&did)?;
+    revoke_fortune(&did, &state.pool, &state.keypair, &config().labeler_did, &state.tx).await?;
+    tracing::info!(did, "Admin revoked fortune");
+    Ok(Json(account_labels(&state, did, None, LIST_LIMIT_DEFAULT).await?))
+}
+
+async fn account_labels(state: &AppState, did: String, cursor: Option<i64>, limit: i64) -> Result<AccountLabelsOutput, XrpcError> {
+    let labels = db::get_labels(&state.pool, &did, None, None).await?
+        .into_iter()
+        .filter(|l| l.neg == 0)
+        .map(|l| CurrentLabel { val: l.val, cts: l.cts, fixed: l.is_fixed.unwrap_or(0) == 1 })
+        .collect();
+    let history = db::get_label_history(&state.pool, &did, cursor, limit).await?;
+    let cursor = if history.len() as i64 == limit {
+        history.last().map(|e| e.seq.to_string())
+    } else {
+        None
+    };
+    Ok(AccountLabelsOutput {
+        opted_out: db::is_opted_out(&state.pool, &did).await?,
+        did,
+        labels,
+        history: history.into_iter().map(LabelHistoryEntry::from).collect(),
+        cursor,
+    })
+}
+
+/// Parses `did` and refuses accounts that have opted out of labeling.
+async fn labelable_did(state: &AppState, did: &str) -> Result<String, XrpcError> {
+    let did = parse_did(did)?;
+    if db::is_opted_out(&state.pool, &did).await? {
+        return Err(XrpcError::InvalidRequest(format!("{} has opted out of labeling", did)));
+    }
+    Ok(did)
+}
+
+fn parse_did(did: &str) -> Result<String, XrpcError> {
+    atrium_api::types::string::Did::new(did.to_string())
+        .map(|d| d.as_str().to_string())
+        .map_err(|e| XrpcError::InvalidRequest(format!("Invalid DID {}: {}", did, e)))
+}
+
+async fn find_report(state: &AppState, id: &str) -> Result<ReportRow, XrpcError> {
+    let id = id.parse::<i64>().map_err(|_| XrpcError::InvalidRequest(format!("Malformed report id: {}", id)))?;
+    db::get_report(&state.pool, id).await?
+        .ok_or_else(|| XrpcError::NotFound(format!("Report {} not found", id)))
+}
+
+fn subject_of(row: &ReportRow) -> Result<&str, XrpcError> {
+    row.subject_did.as_deref()
+        .ok_or_else(|| XrpcError::InvalidRequest(format!("Report {} has no account to label", row.id)))
+}
@@ -0,0 +1,897 @@
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        Router,
+    };
+    use tower::util::ServiceExt;
+    use serde_json::Value;
+    use atrium_api::com::atproto::label::query_labels::Output as QueryLabelsOutput;
+    use atrium_api::com::atproto::moderation::create_report::Output as ReportOutput;
+    use crate::api::router;
+    use crate::state::AppState;
+    use crate::config::init_test_config;
+    use crate::db::{get_labels, get_report, init_db};
+    use crate::domain::fortune::get_daily_fortune;
+    use crate::domain::labeling::{emit_label, sign_new_label};
+    use crate::auth::{sign_service_jwt, StaticDidResolver};
+    use atrium_crypto::keypair::Did;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use std::sync::Arc;
+    use rand::rngs::OsRng;
+    use futures_util::StreamExt;
+    use ipld_core::ipld::Ipld;
+    use serde::Deserialize;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    const REPORTER_DID: &str = "did:plc:reporter";
+    const TARGET_DID: &str = "did:plc:target";
+    const ADMIN_DID: &str = "did:plc:admin";
+    const ADMIN_TOKEN: &str = "test-admin-token";
+
+    /// The key every test account signs its service auth tokens with.
+    fn account_keypair() -> Secp256k1Keypair {
+        Secp256k1Keypair::import(&[1u8; 32]).unwrap()
+    }
+
+    /// An `Authorization` header value for a createReport call made by `did`.
+    fn reporter_auth(did: &str) -> String {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let token = sign_service_jwt(&account_keypair(), did, "did:plc:test", "com.atproto.moderation.createReport", exp);
+        format!("Bearer {}", token)
+    }
+
+    /// A createReport call by `reporter` about `TARGET_DID`.
+    fn report_request(reporter: &str, reason_type: &str, reason: &str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/xrpc/com.atproto.moderation.createReport")
+            .header("Content-Type", "application/json")
+            .header("Authorization", reporter_auth(reporter))
+            .body(Body::from(serde_json::to_vec(&serde_json::json!({
+                "reasonType": reason_type,
+                "reason": reason,
+                "subject": { "$type": "com.atproto.admin.defs#repoRef", "did": TARGET_DID }
+            })).unwrap()))
+            .unwrap()
+    }
+
+    async fn setup_state(capacity: usize) -> AppState {
+        // Handlers read the global config
+        let conf = init_test_config();
+        assert_eq!(conf.admin_dids, [ADMIN_DID]);
+        assert_eq!(conf.admin_token.as_deref(), Some(ADMIN_TOKEN));
+
+        let pool = init_db(":memory:").await.unwrap();
+        let mut rng = OsRng;
+        let keypair = Arc::new(Secp256k1Keypair::create(&mut rng));
+        let state = AppState {
+            pool: pool.clone(),
+            keypair: keypair.clone(),
+            tx: tokio::sync::broadcast::channel(capacity).0,
+            shutdown: Arc::new(tokio::sync::watch::channel(false).0),
+            resolver: Arc::new(StaticDidResolver(
+                [REPORTER_DID, TARGET_DID, ADMIN_DID].iter().map(|did| (did.to_string(), account_keypair().did())).collect(),
+            )),
+        };
+
+        // Pre-insert some data
+        let label = sign_new_label("did:plc:test", "fortune_val", false, "did:plc:labeler", &keypair).unwrap();
+        emit_label(&pool, &state.tx, label).await.unwrap();
+
+        state
+    }
+
+    async fn setup_app() -> Router {
+        router(setup_state(100).await)
+    }
+
+    async fn emit_test_label(state: &AppState, uri: &str) -> i64 {
+        let label = sign_new_label(uri, "kichi", false, "did:plc:labeler", &state.keypair).unwrap();
+        emit_label(&state.pool, &state.tx, label).await.unwrap()
+    }
+
+    /// Serves the router on an ephemeral port and opens a subscribeLabels socket.
+    async fn connect_stream(
+        state: AppState,
+        query: &str,
+    ) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router(state)).await.unwrap();
+        });
+
+        let url = format!("ws://{}/xrpc/com.atproto.label.subscribeLabels{}", addr, query);
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        socket
+    }
+
+    /// Reads the next binary frame and decodes it into `(header, body)`.
+    async fn next_frame<S>(socket: &mut S) -> Option<(Ipld, Ipld)>
+    where
+        S: futures_util::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.expect("Timed out waiting for frame")?;
+            match msg.ok()? {
+                WsMessage::Binary(bytes) => {
+                    let mut de = serde_ipld_dagcbor::de::Deserializer::from_slice(&bytes);
+                    let header = Ipld::deserialize(&mut de).unwrap();
+                    let body = Ipld::deserialize(&mut de).unwrap();
+                    return Some((header, body));
+                }
+                WsMessage::Close(_) => return None,
+                _ => continue,
+            }
+        }
+    }
+
+    fn ipld_field<'a>(ipld: &'a Ipld, key: &str) -> &'a Ipld {
+        match ipld {
+            Ipld::Map(map) => map.get(key).unwrap_or(&Ipld::Null),
+            _ => &Ipld::Null,
+        }
+    }
+
+    fn frame_seq(body: &Ipld) -> i64 {
+        match ipld_field(body, "seq") {
+            Ipld::Integer(seq) => *seq as i64,
+            other => panic!("Frame has no seq: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_labels() {
+        let app = setup_app().await;
+
+        let req = Request::builder()
+            .uri("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=did:plc:test")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        // println!("BODY: {}", body_str);
+
+        let body_json: QueryLabelsOutput = serde_json::from_str(&body_str).unwrap();
+
+        assert_eq!(body_json.labels.len(), 1);
+        assert_eq!(body_json.labels[0].uri, "did:plc:test");
+        assert_eq!(body_json.labels[0].val, "fortune_val");
+    }
+
+    #[tokio::test]
+    async fn test_query_labels_empty() {
+        let app = setup_app().await;
+
+        let req = Request::builder()
+            .uri("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=did:plc:unknown")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: QueryLabelsOutput = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body_json.labels.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let app = setup_app().await;
+
+        let req = Request::builder()
+            .uri("/xrpc/_health")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body_json["version"], "0.0.0");
+        // The poller never runs in tests, so it can't be stuck
+        assert_eq!(body_json["poller"]["running"], false);
+        assert_eq!(body_json["poller"]["stale"], false);
+    }
+
+    #[tokio::test]
+    async fn test_create_report() {
+        let state = setup_state(16).await;
+        let app = router(state.clone());
+
+        let payload = serde_json::json!({
+            "reasonType": "com.atproto.moderation.defs#reasonOther",
+            "reason": "Test report with keyword: daikichi",
+            "subject": {
+                "$type": "com.atproto.repo.strongRef",
+                "uri": "at://did:plc:target/app.bsky.feed.post/3juv3456789",
+                "cid": "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"
+            }
+        });
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/xrpc/com.atproto.moderation.createReport")
+            .header("Content-Type", "application/json")
+            .header("Authorization", reporter_auth(TARGET_DID))
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+
+        let response = app.oneshot(req).await.unwrap();
+
+        // Should be 200 OK even if logic runs
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: ReportOutput = serde_json::from_slice(&body).unwrap(); // Output matches createReport response type
+        assert_eq!(body_json.reason.as_deref(), Some("Test report with keyword: daikichi"));
+        assert_eq!(body_json.reason_type, "com.atproto.moderation.defs#reasonOther");
+        assert_eq!(body_json.reported_by.as_str(), TARGET_DID);
+
+        // The response echoes the stored report, including what it triggered
+        let row = get_report(&state.pool, body_json.id).await.unwrap().expect("report stored");
+        assert_eq!(row.subject_did.as_deref(), Some("did:plc:target"));
+        assert_eq!(row.action.as_deref(), Some("set:daikichi"));
+        assert_eq!(row.created_at, body_json.created_at.as_str());
+
+        // Each report gets its own id
+        let req = Request::builder()
+            .method("POST")
+            .uri("/xrpc/com.atproto.moderation.createReport")
+            .header("Content-Type", "application/json")
+            .header("Authorization", reporter_auth(TARGET_DID))
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let response = router(state).oneshot(req).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let second: ReportOutput = serde_json::from_slice(&body).unwrap();
+        assert!(second.id > body_json.id);
+    }
+    #[tokio::test]
+    async fn test_subscribe_labels_replays_from_cursor() {
+        let state = setup_state(100).await;
+        let second = emit_test_label(&state, "did:plc:second").await;
+        let third = emit_test_label(&state, "did:plc:third").await;
+
+        let mut socket = connect_stream(state.clone(), &format!("?cursor={}", second - 1)).await;
+
+        let (header, body) = next_frame(&mut socket).await.unwrap();
+        assert_eq!(ipld_field(&header, "t"), &Ipld::String("#labels".to_string()));
+        assert_eq!(frame_seq(&body), second);
+        let (_, body) = next_frame(&mut socket).await.unwrap();
+        assert_eq!(frame_seq(&body), third);
+
+        // Live events follow the replay without gaps or duplicates
+        let fourth = emit_test_label(&state, "did:plc:fourth").await;
+        let (_, body) = next_frame(&mut socket).await.unwrap();
+        assert_eq!(frame_seq(&body), fourth);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_labels_future_cursor() {
+        let state = setup_state(100).await;
+        let mut socket = connect_stream(state, "?cursor=9999").await;
+
+        let (header, body) = next_frame(&mut socket).await.unwrap();
+        assert_eq!(ipld_field(&header, "op"), &Ipld::Integer(-1));
+        assert_eq!(ipld_field(&body, "error"), &Ipld::String("FutureCursor".to_string()));
+
+        assert!(next_frame(&mut socket).await.is_none(), "Socket should close after an error frame");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_labels_recovers_from_lag() {
+        // A tiny buffer guarantees the subscriber lags behind the burst below
+        let state = setup_state(1).await;
+        let mut socket = connect_stream(state.clone(), "?cursor=0").await;
+
+        let (_, body) = next_frame(&mut socket).await.unwrap();
+        let mut expected = frame_seq(&body);
+
+        let mut last = expected;
+        for i in 0..20 {
+            last = emit_test_label(&state, &format!("did:plc:burst{}", i)).await;
+        }
+
+        while expected < last {
+            let (_, body) = next_frame(&mut socket).await.expect("Stream ended before catching up");
+            expected += 1;
+            assert_eq!(frame_seq(&body), expected, "Frames must be contiguous after lag recovery");
+        }
+    }
+    #[tokio::test]
+    async fn test_subscribe_labels_closes_on_shutdown() {
+        let state = setup_state(100).await;
+        let mut socket = connect_stream(state.clone(), "?cursor=0").await;
+
+        // Wait until the replay proves the subscriber is live
+        next_frame(&mut socket).await.unwrap();
+        assert_eq!(state.tx.receiver_count(), 1);
+
+        state.shutdown.send_replace(true);
+
+        let close = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(msg) = socket.next().await {
+                if let Ok(WsMessage::Close(frame)) = msg {
+                    return frame;
+                }
+            }
+            None
+        }).await.expect("Timed out waiting for close frame");
+        assert_eq!(close.map(|f| u16::from(f.code)), Some(1001));
+
+        // The subscriber's receiver is released once the handler exits
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.tx.receiver_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Receiver was not released");
+    }
+    #[tokio::test]
+    async fn test_query_labels_matches_stream() {
+        use atrium_crypto::keypair::Did as _;
+
+        let state = setup_state(100).await;
+        let mut rx = state.tx.subscribe();
+        emit_test_label(&state, "did:plc:verbatim").await;
+        let (_, streamed) = rx.try_recv().unwrap();
+
+        let req = Request::builder()
+            .uri("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=did:plc:verbatim")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: QueryLabelsOutput = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json.labels.len(), 1);
+
+        // Same fields, same signature, same canonical bytes
+        let queried = &body_json.labels[0];
+        assert_eq!(queried.data, streamed[0].data);
+        assert_eq!(queried.ver, Some(1));
+        assert_eq!(
+            serde_ipld_dagcbor::to_vec(&queried.data).unwrap(),
+            serde_ipld_dagcbor::to_vec(&streamed[0].data).unwrap(),
+        );
+
+        let mut unsigned = queried.data.clone();
+        unsigned.sig = None;
+        let bytes = serde_ipld_dagcbor::to_vec(&unsigned).unwrap();
+        atrium_crypto::verify::verify_signature(&state.keypair.did(), &bytes, queried.sig.as_ref().unwrap()).unwrap();
+    }
+    #[tokio::test]
+    async fn test_query_labels_prefix_pattern_and_sources() {
+        let state = setup_state(100).await;
+        emit_test_label(&state, "at://did:plc:prefix/app.bsky.feed.post/1").await;
+        emit_test_label(&state, "at://did:plc:prefix/app.bsky.feed.post/2").await;
+        emit_test_label(&state, "at://did:plc:other/app.bsky.feed.post/1").await;
+
+        let query = |uri: &str| {
+            let state = state.clone();
+            let req = Request::builder().uri(uri.to_string()).body(Body::empty()).unwrap();
+            async move { router(state).oneshot(req).await.unwrap() }
+        };
+
+        let response = query("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=at://did:plc:prefix/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: QueryLabelsOutput = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json.labels.len(), 2);
+        assert!(body_json.labels.iter().all(|l| l.uri.starts_with("at://did:plc:prefix/")));
+
+        let response = query("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=at://did:plc:prefix/*&sources[]=did:plc:someone").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: QueryLabelsOutput = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json.labels.len(), 0);
+
+        let response = query("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=at://did:plc:prefix/*&sources[]=did:plc:labeler").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: QueryLabelsOutput = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json.labels.len(), 2);
+
+        let response = query("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=at://*/app.bsky.feed.post/1").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json["error"], "InvalidRequest");
+    }
+    #[tokio::test]
+    async fn test_query_labels_paginates_multiple_patterns() {
+        let state = setup_state(100).await;
+        let mut expected = Vec::new();
+        for uri in ["did:plc:page1", "did:plc:page2", "did:plc:elsewhere", "at://did:plc:page1/app.bsky.feed.post/1", "did:plc:page2"] {
+            // Distinct values so repeated URIs keep both labels current
+            let label = sign_new_label(uri, &format!("v{}", expected.len()), false, "did:plc:labeler", &state.keypair).unwrap();
+            let seq = emit_label(&state.pool, &state.tx, label).await.unwrap();
+            if uri != "did:plc:elsewhere" {
+                expected.push((seq, uri.to_string()));
+            }
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        for _ in 0..10 {
+            let mut uri = "/xrpc/com.atproto.label.queryLabels?uriPatterns[]=did:plc:page1&uriPatterns[]=at://did:plc:page1/*&uriPatterns[]=did:plc:page2&limit=2".to_string();
+            if let Some(c) = &cursor {
+                uri.push_str(&format!("&cursor={}", c));
+            }
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = router(state.clone()).oneshot(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body_json: QueryLabelsOutput = serde_json::from_slice(&body).unwrap();
+            assert!(body_json.labels.len() <= 2);
+            seen.extend(body_json.labels.iter().map(|l| l.uri.clone()));
+
+            cursor = body_json.data.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        assert!(cursor.is_none(), "Pagination should terminate");
+        assert_eq!(seen, expected.into_iter().map(|(_, uri)| uri).collect::<Vec<_>>());
+    }
+
+    async fn assert_xrpc_error(response: axum::response::Response, status: StatusCode, error: &str) {
+        assert_eq!(response.status(), status);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json["error"], error);
+        assert!(body_json["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_xrpc_errors_for_bad_input() {
+        let app = setup_app().await;
+
+        // Malformed cursor
+        let req = Request::builder()
+            .uri("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=did:plc:test&cursor=abc")
+            .body(Body::empty())
+            .unwrap();
+        assert_xrpc_error(app.clone().oneshot(req).await.unwrap(), StatusCode::BAD_REQUEST, "InvalidRequest").await;
+
+        // Missing required parameter
+        let req = Request::builder()
+            .uri("/xrpc/com.atproto.label.queryLabels")
+            .body(Body::empty())
+            .unwrap();
+        assert_xrpc_error(app.clone().oneshot(req).await.unwrap(), StatusCode::BAD_REQUEST, "InvalidRequest").await;
+
+        // Malformed report body
+        let req = Request::builder()
+            .method("POST")
+            .uri("/xrpc/com.atproto.moderation.createReport")
+            .header("Content-Type", "application/json")
+            .header("Authorization", reporter_auth(REPORTER_DID))
+            .body(Body::from(r#"{"reasonType": 1}"#))
+            .unwrap();
+        assert_xrpc_error(app.clone().oneshot(req).await.unwrap(), StatusCode::BAD_REQUEST, "InvalidRequest").await;
+
+        // Unsupported subject is rejected rather than answered with a made-up record
+        let payload = serde_json::json!({
+            "reasonType": "com.atproto.moderation.defs#reasonOther",
+            "subject": { "$type": "com.example.unknown", "id": "x" }
+        });
+        let req = Request::builder()
+            .method("POST")
+            .uri("/xrpc/com.atproto.moderation.createReport")
+            .header("Content-Type", "application/json")
+            .header("Authorization", reporter_auth(REPORTER_DID))
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        assert_xrpc_error(app.clone().oneshot(req).await.unwrap(), StatusCode::BAD_REQUEST, "InvalidRequest").await;
+
+        // Unknown method
+        let req = Request::builder()
+            .uri("/xrpc/com.example.nothing")
+            .body(Body::empty())
+            .unwrap();
+        assert_xrpc_error(app.oneshot(req).await.unwrap(), StatusCode::NOT_IMPLEMENTED, "MethodNotImplemented").await;
+    }
+
+    #[tokio::test]
+    async fn test_query_labels_reports_db_failure() {
+        let state = setup_state(16).await;
+        state.pool.close().await;
+        let app = router(state);
+
+        let req = Request::builder()
+            .uri("/xrpc/com.atproto.label.queryLabels?uriPatterns[]=did:plc:test")
+            .body(Body::empty())
+            .unwrap();
+        assert_xrpc_error(app.oneshot(req).await.unwrap(), StatusCode::INTERNAL_SERVER_ERROR, "InternalServerError").await;
+    }
+
+    #[tokio::test]
+    async fn test_create_report_requires_service_auth() {
+        let state = setup_state(16).await;
+        let payload = serde_json::json!({
+            "reasonType": "com.atproto.moderation.defs#reasonOther",
+            "reason": "daikichi",
+            "subject": { "$type": "com.atproto.admin.defs#repoRef", "did": TARGET_DID }
+        });
+        let report = |auth: Option<String>| {
+            let mut req = Request::builder()
+                .method("POST")
+                .uri("/xrpc/com.atproto.moderation.createReport")
+                .header("Content-Type", "application/json");
+            if let Some(auth) = auth {
+                req = req.header("Authorization", auth);
+            }
+            req.body(Body::from(serde_json::to_vec(&payload).unwrap())).unwrap()
+        };
+        let app = router(state.clone());
+
+        assert_xrpc_error(app.clone().oneshot(report(None)).await.unwrap(), StatusCode::UNAUTHORIZED, "AuthRequired").await;
+        assert_xrpc_error(app.clone().oneshot(report(Some("Bearer nonsense".to_string()))).await.unwrap(), StatusCode::UNAUTHORIZED, "AuthRequired").await;
+
+        // Issuer whose DID document doesn't exist
+        let response = app.clone().oneshot(report(Some(reporter_auth("did:plc:stranger")))).await.unwrap();
+        assert_xrpc_error(response, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+
+        // Token meant for another labeler
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let token = sign_service_jwt(&account_keypair(), REPORTER_DID, "did:plc:elsewhere", "com.atproto.moderation.createReport", exp);
+        let response = app.clone().oneshot(report(Some(format!("Bearer {}", token)))).await.unwrap();
+        assert_xrpc_error(response, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+
+        // Nothing was stored or labeled for rejected requests
+        assert!(get_report(&state.pool, 1).await.unwrap().is_none());
+
+        let response = app.oneshot(report(Some(reporter_auth(TARGET_DID)))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_create_report_only_changes_own_fortune() {
+        let state = setup_state(16).await;
+        let report = |reporter: &str| report_request(reporter, "com.atproto.moderation.defs#reasonOther", "大凶");
+        let app = router(state.clone());
+
+        // Someone else trying to brand the target is refused, but the attempt is kept
+        let response = app.clone().oneshot(report(REPORTER_DID)).await.unwrap();
+        assert_xrpc_error(response, StatusCode::FORBIDDEN, "Forbidden").await;
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+        let row = get_report(&state.pool, 1).await.unwrap().expect("attempt recorded");
+        assert_eq!(row.reported_by, REPORTER_DID);
+        assert_eq!(row.action.as_deref(), Some("denied:set:daikyo"));
+
+        // Admins may change anyone's fortune
+        let response = app.oneshot(report(ADMIN_DID)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let labels = get_labels(&state.pool, TARGET_DID, None, None).await.unwrap();
+        assert!(labels.iter().any(|l| l.val == "daikyo" && l.neg == 0));
+    }
+
+    #[tokio::test]
+    async fn test_create_report_runs_commands() {
+        let state = setup_state(16).await;
+        let report = |reason: &str| report_request(TARGET_DID, "com.atproto.moderation.defs#reasonOther", reason);
+        let app = router(state.clone());
+        let action = |id: i64| {
+            let pool = state.pool.clone();
+            async move { get_report(&pool, id).await.unwrap().unwrap().action }
+        };
+
+        // A negated fortune is not a request for it
+        assert_eq!(app.clone().oneshot(report("not daikichi")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(action(1).await, None);
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+
+        assert_eq!(app.clone().oneshot(report("引き直し")).await.unwrap().status(), StatusCode::OK);
+        let rerolled = action(2).await.unwrap();
+        let val = rerolled.strip_prefix("reroll:").expect("reroll recorded");
+        let labels = get_labels(&state.pool, TARGET_DID, None, None).await.unwrap();
+        assert!(labels.iter().any(|l| l.val == val && l.neg == 0));
+
+        assert_eq!(app.oneshot(report("opt out")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(action(3).await.as_deref(), Some("opt_out"));
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_report_daily_quota() {
+        let state = setup_state(16).await;
+        let report = |reporter: &str| report_request(reporter, "com.atproto.moderation.defs#reasonOther", "reroll");
+        let app = router(state.clone());
+
+        for remaining in (0..3).rev() {
+            let response = app.clone().oneshot(report(TARGET_DID)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["RateLimit-Limit"], "3");
+            assert_eq!(response.headers()["RateLimit-Remaining"], remaining.to_string().as_str());
+        }
+
+        let response = app.clone().oneshot(report(TARGET_DID)).await.unwrap();
+        assert_eq!(response.headers()["RateLimit-Remaining"], "0");
+        let reset: i64 = response.headers()["RateLimit-Reset"].to_str().unwrap().parse().unwrap();
+        assert!(reset > chrono::Utc::now().timestamp());
+        assert_xrpc_error(response, StatusCode::TOO_MANY_REQUESTS, "RateLimitExceeded").await;
+        assert_eq!(get_report(&state.pool, 4).await.unwrap().unwrap().action.as_deref(), Some("rate_limited:reroll"));
+
+        // Opting out and back in are never limited
+        for reason in ["opt out", "opt in"] {
+            let response = app.clone().oneshot(report_request(TARGET_DID, "com.atproto.moderation.defs#reasonOther", reason)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", reason);
+            assert!(response.headers().get("RateLimit-Remaining").is_none());
+        }
+        assert_eq!(get_report(&state.pool, 5).await.unwrap().unwrap().action.as_deref(), Some("opt_out"));
+
+        // Admins aren't limited
+        let response = app.oneshot(report(ADMIN_DID)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("RateLimit-Remaining").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_create_report_failed_command_keeps_quota() {
+        let state = setup_state(16).await;
+        let report = || report_request(TARGET_DID, "com.atproto.moderation.defs#reasonOther", "reroll");
+        let app = router(state.clone());
+
+        sqlx::query("CREATE TRIGGER broken BEFORE INSERT ON label_events BEGIN SELECT RAISE(ABORT, 'broken'); END")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let response = app.clone().oneshot(report()).await.unwrap();
+        assert_xrpc_error(response, StatusCode::INTERNAL_SERVER_ERROR, "InternalServerError").await;
+
+        sqlx::query("DROP TRIGGER broken").execute(&state.pool).await.unwrap();
+        let response = app.oneshot(report()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["RateLimit-Remaining"], "2");
+    }
+
+    #[tokio::test]
+    async fn test_create_report_routes_by_reason_type() {
+        let state = setup_state(16).await;
+        let app = router(state.clone());
+        let positive = || {
+            let pool = state.pool.clone();
+            async move {
+                get_labels(&pool, TARGET_DID, None, None).await.unwrap()
+                    .into_iter().filter(|l| l.neg == 0).map(|l| l.val).collect::<Vec<_>>()
+            }
+        };
+
+        // Abuse reports are only queued, even when they mention a fortune
+        let response = app.clone().oneshot(report_request(REPORTER_DID, "com.atproto.moderation.defs#reasonSpam", "大凶")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get_report(&state.pool, 1).await.unwrap().unwrap().action.as_deref(), Some("queued"));
+        assert!(positive().await.is_empty());
+
+        // An appeal undoes a manual override
+        let daily = get_daily_fortune(TARGET_DID);
+        let forced = if daily.as_str() == "daikyo" { "大吉" } else { "大凶" };
+        let response = app.clone().oneshot(report_request(TARGET_DID, "com.atproto.moderation.defs#reasonOther", forced)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(positive().await, vec![daily.as_str().to_string()]);
+
+        let response = app.clone().oneshot(report_request(TARGET_DID, "com.atproto.moderation.defs#reasonAppeal", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get_report(&state.pool, 3).await.unwrap().unwrap().action, Some(format!("restore:{}", daily)));
+        assert_eq!(positive().await, vec![daily.as_str().to_string()]);
+
+        // Appeals are commands on the subject, so they follow the same rules
+        let response = app.clone().oneshot(report_request(REPORTER_DID, "com.atproto.moderation.defs#reasonAppeal", "")).await.unwrap();
+        assert_xrpc_error(response, StatusCode::FORBIDDEN, "Forbidden").await;
+
+        let response = app.oneshot(report_request(TARGET_DID, "com.example.reasonWhatever", "daikichi")).await.unwrap();
+        assert_xrpc_error(response, StatusCode::BAD_REQUEST, "InvalidRequest").await;
+    }
+
+    async fn admin_call(app: &Router, method: &str, uri: &str, token: Option<&str>, body: Option<Value>) -> axum::response::Response {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        let body = match body {
+            Some(body) => {
+                req = req.header("Content-Type", "application/json");
+                Body::from(serde_json::to_vec(&body).unwrap())
+            }
+            None => Body::empty(),
+        };
+        app.clone().oneshot(req.body(body).unwrap()).await.unwrap()
+    }
+
+    async fn json_body(response: axum::response::Response) -> Value {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_report_review() {
+        let state = setup_state(16).await;
+        let app = router(state.clone());
+        for reason_type in ["com.atproto.moderation.defs#reasonSpam", "com.atproto.moderation.defs#reasonRude", "com.atproto.moderation.defs#reasonOther"] {
+            let req = report_request(REPORTER_DID, reason_type, "please look at this");
+            assert_eq!(app.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+        }
+
+        assert_xrpc_error(admin_call(&app, "GET", "/admin/reports", None, None).await, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+        assert_xrpc_error(admin_call(&app, "GET", "/admin/reports", Some("wrong"), None).await, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+
+        // Only the queued abuse reports are open
+        let open = json_body(admin_call(&app, "GET", "/admin/reports", Some(ADMIN_TOKEN), None).await).await;
+        let ids: Vec<i64> = open["reports"].as_array().unwrap().iter().map(|r| r["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 2]);
+        let rude = json_body(admin_call(&app, "GET", "/admin/reports?reasonType=com.atproto.moderation.defs%23reasonRude", Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(rude["reports"].as_array().unwrap().len(), 1);
+
+        let report = json_body(admin_call(&app, "GET", "/admin/reports/1", Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(report["subjectDid"], TARGET_DID);
+        assert_eq!(report["reportedBy"], REPORTER_DID);
+        assert_eq!(report["subject"]["did"], TARGET_DID);
+        assert_xrpc_error(admin_call(&app, "GET", "/admin/reports/99", Some(ADMIN_TOKEN), None).await, StatusCode::NOT_FOUND, "NotFound").await;
+
+        // Applying a label goes through the normal labeling path, so it shows up in queryLabels
+        let resolve = serde_json::json!({ "action": "apply", "val": "daikyo", "note": "confirmed" });
+        let resolved = json_body(admin_call(&app, "POST", "/admin/reports/1/resolve", Some(ADMIN_TOKEN), Some(resolve.clone())).await).await;
+        assert_eq!(resolved["resolution"], "apply:daikyo");
+        assert_eq!(resolved["note"], "confirmed");
+        assert!(resolved["resolvedAt"].is_string());
+        let labels = json_body(admin_call(&app, "GET", &format!("/xrpc/com.atproto.label.queryLabels?uriPatterns[]={}", TARGET_DID), None, None).await).await;
+        assert!(labels["labels"].as_array().unwrap().iter().any(|l| l["val"] == "daikyo" && l["neg"] != true));
+
+        let response = admin_call(&app, "POST", "/admin/reports/1/resolve", Some(ADMIN_TOKEN), Some(resolve)).await;
+        assert_xrpc_error(response, StatusCode::BAD_REQUEST, "InvalidRequest").await;
+        let response = admin_call(&app, "POST", "/admin/reports/2/resolve", Some(ADMIN_TOKEN), Some(serde_json::json!({ "action": "apply", "val": "nope" }))).await;
+        assert_xrpc_error(response, StatusCode::BAD_REQUEST, "InvalidRequest").await;
+
+        // A report whose labels could not be changed stays open
+        sqlx::query("CREATE TRIGGER broken BEFORE INSERT ON label_events BEGIN SELECT RAISE(ABORT, 'broken'); END")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let response = admin_call(&app, "POST", "/admin/reports/2/resolve", Some(ADMIN_TOKEN), Some(serde_json::json!({ "action": "apply", "val": "kichi" }))).await;
+        assert_xrpc_error(response, StatusCode::INTERNAL_SERVER_ERROR, "InternalServerError").await;
+        sqlx::query("DROP TRIGGER broken").execute(&state.pool).await.unwrap();
+        let report = json_body(admin_call(&app, "GET", "/admin/reports/2", Some(ADMIN_TOKEN), None).await).await;
+        assert!(report["resolution"].is_null());
+        assert!(report["resolvedAt"].is_null());
+
+        // Negating removes the subject's labels
+        let negate = serde_json::json!({ "action": "negate" });
+        json_body(admin_call(&app, "POST", "/admin/reports/2/resolve", Some(ADMIN_TOKEN), Some(negate)).await).await;
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+
+        let open = json_body(admin_call(&app, "GET", "/admin/reports", Some(ADMIN_TOKEN), None).await).await;
+        assert!(open["reports"].as_array().unwrap().is_empty());
+        let resolved = json_body(admin_call(&app, "GET", "/admin/reports?status=resolved", Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(resolved["reports"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_opt_out() {
+        let state = setup_state(16).await;
+        let report = |reason: &str| report_request(TARGET_DID, "com.atproto.moderation.defs#reasonOther", reason);
+        let app = router(state.clone());
+
+        assert_eq!(app.clone().oneshot(report("reveal")).await.unwrap().status(), StatusCode::OK);
+        assert!(!get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+
+        // Opting out revokes right away
+        assert_eq!(app.clone().oneshot(report("opt out")).await.unwrap().status(), StatusCode::OK);
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+        let opt_outs = json_body(admin_call(&app, "GET", "/admin/opt-outs", Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(opt_outs["optOuts"][0]["did"], TARGET_DID);
+        assert_eq!(opt_outs["optOuts"][0]["source"], "report");
+
+        // Other commands are refused until the account opts back in
+        let response = app.clone().oneshot(report("daikichi")).await.unwrap();
+        assert_xrpc_error(response, StatusCode::FORBIDDEN, "Forbidden").await;
+        let row = get_report(&state.pool, 3).await.unwrap().expect("attempt recorded");
+        assert_eq!(row.action.as_deref(), Some("opted_out:set:daikichi"));
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+
+        assert_eq!(app.clone().oneshot(report("opt in")).await.unwrap().status(), StatusCode::OK);
+        assert!(!get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+
+        // Admins can manage the registry directly
+        let uri = format!("/admin/opt-outs/{}", REPORTER_DID);
+        assert_xrpc_error(admin_call(&app, "PUT", &uri, None, None).await, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+        let opt_outs = json_body(admin_call(&app, "PUT", &uri, Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(opt_outs["optOuts"].as_array().unwrap().len(), 1);
+        assert_eq!(opt_outs["optOuts"][0]["did"], REPORTER_DID);
+        assert_eq!(opt_outs["optOuts"][0]["source"], "admin");
+        let opt_outs = json_body(admin_call(&app, "DELETE", &uri, Some(ADMIN_TOKEN), None).await).await;
+        assert!(opt_outs["optOuts"].as_array().unwrap().is_empty());
+        assert_xrpc_error(admin_call(&app, "PUT", "/admin/opt-outs/not-a-did", Some(ADMIN_TOKEN), None).await, StatusCode::BAD_REQUEST, "InvalidRequest").await;
+    }
+
+    #[tokio::test]
+    async fn test_admin_label_management() {
+        let state = setup_state(16).await;
+        let app = router(state.clone());
+        let uri = format!("/admin/labels/{}", TARGET_DID);
+        let positive = |body: &Value| -> Vec<String> {
+            body["labels"].as_array().unwrap().iter().map(|l| l["val"].as_str().unwrap().to_string()).collect()
+        };
+
+        assert_xrpc_error(admin_call(&app, "GET", &uri, None, None).await, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+        let set = serde_json::json!({ "val": "daikyo" });
+        assert_xrpc_error(admin_call(&app, "PUT", &uri, Some("wrong"), Some(set.clone())).await, StatusCode::UNAUTHORIZED, "AuthRequired").await;
+
+        let body = json_body(admin_call(&app, "GET", &uri, Some(ADMIN_TOKEN), None).await).await;
+        assert!(body["labels"].as_array().unwrap().is_empty());
+        assert!(body["history"].as_array().unwrap().is_empty());
+
+        let body = json_body(admin_call(&app, "PUT", &uri, Some(ADMIN_TOKEN), Some(set)).await).await;
+        assert_eq!(positive(&body), vec!["daikyo"]);
+        assert_eq!(body["labels"][0]["fixed"], true);
+        let response = admin_call(&app, "PUT", &uri, Some(ADMIN_TOKEN), Some(serde_json::json!({ "val": "nope" }))).await;
+        assert_xrpc_error(response, StatusCode::BAD_REQUEST, "InvalidRequest").await;
+
+        let body = json_body(admin_call(&app, "POST", &format!("{}/reroll", uri), Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(positive(&body).len(), 1);
+        assert_eq!(body["labels"][0]["fixed"], true);
+
+        let body = json_body(admin_call(&app, "POST", &format!("{}/restore", uri), Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(positive(&body), vec![get_daily_fortune(TARGET_DID).to_string()]);
+        assert_eq!(body["labels"][0]["fixed"], false);
+
+        let body = json_body(admin_call(&app, "DELETE", &uri, Some(ADMIN_TOKEN), None).await).await;
+        assert!(body["labels"].as_array().unwrap().is_empty());
+        assert!(get_labels(&state.pool, TARGET_DID, None, None).await.unwrap().is_empty());
+
+        // History is newest first and pages by seq
+        let history = json_body(admin_call(&app, "GET", &format!("{}?limit=1", uri), Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(history["history"][0]["neg"], true);
+        let seq = history["history"][0]["seq"].as_i64().unwrap();
+        assert_eq!(history["cursor"], seq.to_string());
+        let next = json_body(admin_call(&app, "GET", &format!("{}?limit=1&cursor={}", uri, seq), Some(ADMIN_TOKEN), None).await).await;
+        assert!(next["history"][0]["seq"].as_i64().unwrap() < seq);
+        let all = json_body(admin_call(&app, "GET", &format!("{}?limit=100", uri), Some(ADMIN_TOKEN), None).await).await;
+        assert!(all["history"].as_array().unwrap().iter().any(|e| e["val"] == "daikyo" && e["neg"] == false));
+
+        // Opted-out accounts can't be labeled, only revoked
+        json_body(admin_call(&app, "PUT", &format!("/admin/opt-outs/{}", TARGET_DID), Some(ADMIN_TOKEN), None).await).await;
+        let response = admin_call(&app, "POST", &format!("{}/reroll", uri), Some(ADMIN_TOKEN), None).await;
+        assert_xrpc_error(response, StatusCode::BAD_REQUEST, "InvalidRequest").await;
+        let body = json_body(admin_call(&app, "GET", &uri, Some(ADMIN_TOKEN), None).await).await;
+        assert_eq!(body["optedOut"], true);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let state = setup_state(16).await;
+        let app = router(state.clone());
+        let label = sign_new_label(TARGET_DID, "kyo", true, "did:plc:test", &state.keypair).unwrap();
+        emit_label(&state.pool, &state.tx, label).await.unwrap();
+
+        let response = app.oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/plain; version=0.0.4");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+
+        // Other tests share the process-wide metrics, so only check what this one caused
+        let emitted = text.lines()
+            .find_map(|l| l.strip_prefix("omikuji_labels_emitted_total{val=\"kyo\",neg=\"true\"} "))
+            .expect("emitted counter");
+        assert!(emitted.parse::<u64>().unwrap() >= 1);
+        assert!(text.contains("# TYPE omikuji_stream_subscribers gauge"));
+        assert!(text.contains("omikuji_db_query_duration_seconds_count{query=\"append_label_event\"}"));
+    }
+}
@@ -0,0 +1,104 @@
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// An XRPC error, rendered as `{"error": "...", "message": "..."}` with the matching status code.
+#[derive(Debug, thiserror::Error)]
+pub enum XrpcError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    AuthRequired(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    RateLimitExceeded(String, RateLimit),
+    #[error("{0}")]
+    MethodNotImplemented(String),
+    #[error(transparent)]
+    InternalServerError(#[from] anyhow::Error),
+}
+
+impl XrpcError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            XrpcError::InvalidRequest(_) => "InvalidRequest",
+            XrpcError::AuthRequired(_) => "AuthRequired",
+            XrpcError::Forbidden(_) => "Forbidden",
+            XrpcError::NotFound(_) => "NotFound",
+            XrpcError::RateLimitExceeded(..) => "RateLimitExceeded",
+            XrpcError::MethodNotImplemented(_) => "MethodNotImplemented",
+            XrpcError::InternalServerError(_) => "InternalServerError",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            XrpcError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            XrpcError::AuthRequired(_) => StatusCode::UNAUTHORIZED,
+            XrpcError::Forbidden(_) => StatusCode::FORBIDDEN,
+            XrpcError::NotFound(_) => StatusCode::NOT_FOUND,
+            XrpcError::RateLimitExceeded(..) => StatusCode::TOO_MANY_REQUESTS,
+            XrpcError::MethodNotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            XrpcError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for XrpcError {
+    fn into_response(self) -> Response {
+        let message = match &self {
+            XrpcError::InternalServerError(e) => {
+                // Don't leak internals to clients
+                tracing::error!(error = ?e, "Internal server error");
+                "Internal Server Error".to_string()
+            }
+            other => other.to_string(),
+        };
+
+        let headers = match &self {
+            XrpcError::RateLimitExceeded(_, limit) => limit.headers(),
+            _ => HeaderMap::new(),
+        };
+        (self.status(), headers, Json(json!({ "error": self.name(), "message": message }))).into_response()
+    }
+}
+
+/// The state of a quota, sent as `RateLimit-*` headers like the PDS does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub limit: i64,
+    pub remaining: i64,
+    /// Unix time at which the quota refills.
+    pub reset: i64,
+}
+
+impl RateLimit {
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("RateLimit-Reset", HeaderValue::from(self.reset));
+        headers
+    }
+}
+
+impl From<crate::auth::AuthError> for XrpcError {
+    fn from(e: crate::auth::AuthError) -> Self {
+        match e {
+            crate::auth::AuthError::ResolverUnavailable => XrpcError::InternalServerError(e.into()),
+            e => XrpcError::AuthRequired(e.to_string()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for XrpcError {
+    fn from(e: sqlx::Error) -> Self {
+        XrpcError::InternalServerError(e.into())
+    }
+}
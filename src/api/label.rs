@@ -1,71 +1,60 @@
 use axum::{Json, extract::State};
-use crate::api::QsQuery;
-use atrium_api::com::atproto::label::query_labels::{Parameters, Output, OutputData};
-use atrium_api::com::atproto::label::defs::Label;
-use crate::db::get_labels;
-use crate::config::config;
-use crate::crypto::sign_label;
-use atrium_api::types::string::{Did, Datetime};
-use chrono::SubsecRound;
+use crate::api::{error::XrpcError, QsQuery};
+use atrium_api::com::atproto::label::query_labels::{Output, OutputData};
+use crate::db::{get_label_events, UriPattern, QUERY_LIMIT_DEFAULT, QUERY_LIMIT_MAX};
+use crate::domain::labeling::label_from_event;
 use crate::state::AppState;
+use serde::Deserialize;
 use tracing;
 
+/// Query parameters of `com.atproto.label.queryLabels`.
+///
+/// The atrium `Parameters` type flattens its fields, which stops numbers from parsing out of a query string.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryLabelsParams {
+    uri_patterns: Vec<String>,
+    sources: Option<Vec<String>>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
 pub async fn query_labels(
     State(state): State<AppState>,
-    QsQuery(params): QsQuery<Parameters>,
-) -> Json<Output> {
-    let cursor = params.cursor.clone().and_then(|c| c.parse::<i64>().ok());
-
-    tracing::debug!(?params.data.uri_patterns, ?params.cursor, ?params.limit, "REQ queryLabels");
-
-    let input = params.data;
-    let mut labels = Vec::new();
-    let labeler_did = &config().labeler_did;
-    let mut last_id = 0;
-
-    for pattern in input.uri_patterns {
-        let rows = get_labels(&state.pool, &pattern, cursor, input.limit.map(|l| u8::from(l).into())).await.unwrap_or_else(|_| vec![]);
-
-        for row in rows {
-            if row.id > last_id {
-                last_id = row.id;
-            }
-            let uri = row.uri;
-            let val = row.val;
-            let cts_str = row.cts;
-            let neg_int = row.neg;
-            let src = row.src;
-
-            let cts_parsed = chrono::DateTime::parse_from_rfc3339(&cts_str)
-                .unwrap_or_else(|_| chrono::Utc::now().with_timezone(&chrono::FixedOffset::east_opt(0).unwrap())).round_subsecs(3);
-
-            let mut label_data = atrium_api::com::atproto::label::defs::LabelData {
-                cid: None,
-                cts: Datetime::new(cts_parsed),
-                exp: None,
-                neg: if neg_int != 0 { Some(true) } else { None },
-                sig: None,
-                src: Did::new(src).unwrap_or_else(|_| Did::new(labeler_did.clone()).unwrap()),
-                uri,
-                val,
-                ver: None,
-            };
-
-            if let Err(e) = sign_label(&mut label_data, &state.keypair) {
-                tracing::error!(error = ?e, "Failed to sign label");
-                continue;
-            }
-
-            labels.push(Label::from(label_data));
-        }
+    QsQuery(input): QsQuery<QueryLabelsParams>,
+) -> Result<Json<Output>, XrpcError> {
+    tracing::debug!(?input.uri_patterns, ?input.sources, ?input.cursor, ?input.limit, "REQ queryLabels");
+
+    let cursor = input.cursor.as_deref()
+        .map(|c| c.parse::<i64>().map_err(|_| XrpcError::InvalidRequest(format!("Malformed cursor: {}", c))))
+        .transpose()?;
+    let patterns = input.uri_patterns.iter()
+        .map(|p| UriPattern::parse(p))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| XrpcError::InvalidRequest(e.to_string()))?;
+    let sources = input.sources.unwrap_or_default();
+    let limit = input.limit.unwrap_or(QUERY_LIMIT_DEFAULT).clamp(1, QUERY_LIMIT_MAX);
+
+    // One query across every pattern, so the cursor is a single position in seq order
+    let rows = get_label_events(&state.pool, &patterns, &sources, cursor, Some(limit)).await?;
+
+    // A full page means there may be more after the last seq
+    let next_cursor = if rows.len() as i64 == limit {
+        rows.last().map(|r| r.seq.to_string())
+    } else {
+        None
+    };
+
+    let mut labels = Vec::with_capacity(rows.len());
+    for row in rows {
+        // Served exactly as signed at emission time, so it matches the stream byte for byte
+        labels.push(label_from_event(row)?);
     }
 
-    let next_cursor = if last_id > 0 { Some(last_id.to_string()) } else { None };
-
     tracing::debug!(count = labels.len(), ?next_cursor, "RES queryLabels");
 
-    Json(OutputData {
+    Ok(Json(OutputData {
         cursor: next_cursor,
         labels,
-    }.into())
+    }.into()))
 }
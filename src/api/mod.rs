@@ -1,27 +1,73 @@
 use axum::{
     async_trait,
-    extract::FromRequestParts,
+    extract::{FromRequest, FromRequestParts, Request},
     http::request::Parts,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 
+pub mod admin;
+pub mod error;
 pub mod label;
 pub mod report;
 pub mod websocket;
-mod tests;
+mod api_tests;
 
 pub struct QsQuery<T>(pub T);
 
+/// A JSON body whose rejection is reported as an XRPC `InvalidRequest`.
+pub struct XrpcJson<T>(pub T);
+
+/// The DID of the account that made the request, taken from a verified service auth token.
+pub struct ServiceAuth(pub String);
+
+/// A request carrying the admin bearer token from the config.
+pub struct AdminAuth;
+
+use crate::auth::{verify_service_jwt, AuthError};
+use crate::config::config;
 use crate::state::AppState;
+use error::XrpcError;
+
+/// Reports the version, and fails with 503 while the notification poller is stuck.
+async fn health() -> impl axum::response::IntoResponse {
+    let poller = crate::poller::liveness().status(chrono::Utc::now().timestamp());
+    let last_success = poller.last_success
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    let status = if poller.stale { axum::http::StatusCode::SERVICE_UNAVAILABLE } else { axum::http::StatusCode::OK };
+    (status, axum::Json(serde_json::json!({
+        "version": "0.0.0",
+        "poller": {
+            "running": poller.running,
+            "lastSuccessAt": last_success,
+            "stale": poller.stale,
+        },
+    })))
+}
 
 pub fn router(state: AppState) -> Router {
     Router::new()
         .route("/xrpc/com.atproto.label.queryLabels", get(label::query_labels))
         .route("/xrpc/com.atproto.label.subscribeLabels", get(websocket::subscribe_labels))
         .route("/xrpc/com.atproto.moderation.createReport", post(report::create_report))
-        .route("/xrpc/_health", get(|| async { axum::Json(serde_json::json!({ "version": "0.0.0" })) }))
+        .route("/xrpc/_health", get(health))
+        .route("/metrics", get(|| async {
+            ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], crate::metrics::metrics().render())
+        }))
+        .route("/admin/reports", get(admin::list_reports))
+        .route("/admin/reports/:id", get(admin::get_report))
+        .route("/admin/reports/:id/resolve", post(admin::resolve_report))
+        .route("/admin/opt-outs", get(admin::list_opt_outs))
+        .route("/admin/opt-outs/:did", put(admin::add_opt_out).delete(admin::remove_opt_out))
+        .route("/admin/labels/:did", get(admin::get_account_labels).put(admin::set_account_label).delete(admin::revoke_account_label))
+        .route("/admin/labels/:did/reroll", post(admin::reroll_account_label))
+        .route("/admin/labels/:did/restore", post(admin::restore_account_label))
+        .fallback(|uri: axum::http::Uri| async move {
+            XrpcError::MethodNotImplemented(format!("Method not implemented: {}", uri.path()))
+        })
         .with_state(state)
 }
 
@@ -31,16 +77,69 @@ where
     T: DeserializeOwned,
     S: Send + Sync,
 {
-    type Rejection = axum::http::StatusCode;
+    type Rejection = XrpcError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         let query = parts.uri.query().unwrap_or("");
-        match serde_qs::from_str(query) {
-            Ok(v) => Ok(QsQuery(v)),
-            Err(e) => {
-                eprintln!("serde_qs error: {}", e);
-                Err(axum::http::StatusCode::BAD_REQUEST)
-            }
+        serde_qs::from_str(query)
+            .map(QsQuery)
+            .map_err(|e| XrpcError::InvalidRequest(format!("Invalid query parameters: {}", e)))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ServiceAuth {
+    type Rejection = XrpcError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(parts).ok_or(AuthError::Missing)?;
+        // The token must be scoped to the method being called
+        let lxm = parts.uri.path().trim_start_matches("/xrpc/");
+
+        let did = verify_service_jwt(token.trim(), &config().labeler_did, lxm, state.resolver.as_ref()).await?;
+        Ok(ServiceAuth(did))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminAuth
+where
+    S: Send + Sync,
+{
+    type Rejection = XrpcError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(expected) = config().admin_token.as_deref() else {
+            return Err(XrpcError::Forbidden("Admin API is disabled".to_string()));
+        };
+        let token = bearer_token(parts).ok_or_else(|| XrpcError::AuthRequired("Admin token required".to_string()))?;
+
+        // Compare digests so the time taken doesn't depend on how much of the token matched
+        if Sha256::digest(token.trim()) != Sha256::digest(expected) {
+            return Err(XrpcError::AuthRequired("Invalid admin token".to_string()));
         }
+        Ok(AdminAuth)
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts.headers.get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+#[async_trait]
+impl<S, T> FromRequest<S> for XrpcJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = XrpcError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        axum::Json::<T>::from_request(req, state)
+            .await
+            .map(|axum::Json(v)| XrpcJson(v))
+            .map_err(|e| XrpcError::InvalidRequest(e.body_text()))
     }
 }
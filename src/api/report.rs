@@ -1,104 +1,233 @@
-use axum::{Json, extract::State};
-use atrium_api::com::atproto::moderation::create_report::{Input, Output, OutputSubjectRefs, OutputData};
-use chrono::FixedOffset;
+use axum::{Json, extract::State, http::HeaderMap};
+use crate::api::{error::{RateLimit, XrpcError}, ServiceAuth, XrpcJson};
+use atrium_api::com::atproto::moderation::create_report::{Input, InputSubjectRefs, Output, OutputSubjectRefs, OutputData};
 use crate::state::AppState;
 use crate::config::config;
-use crate::domain::fortune::FORTUNES;
-use crate::domain::labeling::overwrite_fortune;
+use crate::db::{get_report, insert_report, refund_override_quota, set_report_action, take_override_quota, DbPool, NewReport, ReportRow};
+use crate::domain::commands::{parse_command, Command};
+use crate::domain::fortune::{next_day_jst_timestamp, random_fortune, today_jst};
+use crate::domain::labeling::{assign_fortune, opt_in, opt_out, overwrite_fortune, restore_daily_fortune, OptedOut};
+use crate::metrics::metrics;
+use atrium_api::com::atproto::moderation::defs::{REASON_APPEAL, REASON_MISLEADING, REASON_OTHER, REASON_RUDE, REASON_SEXUAL, REASON_SPAM, REASON_VIOLATION};
 use atrium_api::types::string::{Did, Datetime};
-use atrium_api::com::atproto::repo::strong_ref::MainData;
-use ipld_core::ipld::Ipld;
-use cid::Cid;
+use atrium_api::types::Union;
 use std::str::FromStr;
 use tracing;
 
+/// What is done with a report, decided by its `reasonType`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportRoute {
+    /// Drop a manual override and go back to the daily fortune.
+    Appeal,
+    /// Run the command written in the reason.
+    Command,
+    /// Keep for a human to review without touching any labels.
+    Review,
+}
+
+/// Every `reasonType` the labeler accepts. This is also what the labeler service record advertises.
+pub const REPORT_ROUTES: &[(&str, ReportRoute)] = &[
+    (REASON_OTHER, ReportRoute::Command),
+    (REASON_APPEAL, ReportRoute::Appeal),
+    (REASON_SPAM, ReportRoute::Review),
+    (REASON_VIOLATION, ReportRoute::Review),
+    (REASON_MISLEADING, ReportRoute::Review),
+    (REASON_SEXUAL, ReportRoute::Review),
+    (REASON_RUDE, ReportRoute::Review),
+];
+
+impl ReportRoute {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportRoute::Appeal => "appeal",
+            ReportRoute::Command => "command",
+            ReportRoute::Review => "review",
+        }
+    }
+}
+
+pub fn report_route(reason_type: &str) -> Option<ReportRoute> {
+    REPORT_ROUTES.iter().find(|(t, _)| *t == reason_type).map(|(_, route)| *route)
+}
+
 pub async fn create_report(
     State(state): State<AppState>,
-    Json(input): Json<Input>,
-) -> Json<Output> {
-use atrium_api::com::atproto::moderation::create_report::InputSubjectRefs;
-use atrium_api::types::Union;
+    ServiceAuth(reporter): ServiceAuth,
+    XrpcJson(input): XrpcJson<Input>,
+) -> Result<(HeaderMap, Json<Output>), XrpcError> {
+    let subject_did = match &input.subject {
+        Union::Refs(InputSubjectRefs::ComAtprotoAdminDefsRepoRef(r)) => Some(r.did.as_str()),
+        Union::Refs(InputSubjectRefs::ComAtprotoRepoStrongRefMain(r)) => match Did::new(r.uri.clone()) {
+             Ok(_) => Some(r.uri.as_str()),
+             _ => if r.uri.starts_with("at://") {
+                 r.uri.split('/').nth(2)
+             } else {
+                 None
+             }
+        },
+        _ => return Err(XrpcError::InvalidRequest("Unsupported report subject type".to_string())),
+    };
+
+    let route = report_route(&input.reason_type)
+        .ok_or_else(|| XrpcError::InvalidRequest(format!("Unsupported reasonType: {}", input.reason_type)))?;
 
-    if let Some(reason) = &input.reason {
-        let mut best_match: Option<&str> = None;
-        let mut best_len = 0;
+    let created_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let subject = serde_json::to_string(&input.subject).map_err(anyhow::Error::from)?;
+    let id = insert_report(&mut *state.pool.acquire().await?, NewReport {
+        subject: &subject,
+        subject_did,
+        reason_type: &input.reason_type,
+        reason: input.reason.as_deref(),
+        reported_by: &reporter,
+        created_at: &created_at,
+    }).await?;
 
-        for f in FORTUNES {
-            if reason.contains(f.val.as_str()) {
-                if f.val.as_str().len() > best_len {
-                    best_match = Some(f.val.as_str());
-                    best_len = f.val.as_str().len();
+    let command = match route {
+        ReportRoute::Review => {
+            tracing::info!(id, reason_type = input.reason_type, subject_did, reporter, "Report queued for review");
+            record_action(&state, id, route, "queued").await?;
+            None
+        }
+        ReportRoute::Appeal => Some(Command::Restore),
+        ReportRoute::Command => match &input.reason {
+            Some(reason) => {
+                let command = parse_command(reason);
+                if command.is_none() {
+                    tracing::debug!(reason, "Gimmick: No command found in reason");
                 }
+                command
             }
-            if reason.contains(f.label) {
-                if f.label.len() > best_len {
-                    best_match = Some(f.val.as_str());
-                    best_len = f.label.len();
-                }
+            None => {
+                tracing::debug!("Gimmick: No reason provided in report");
+                None
             }
-        }
-
-        if let Some(val) = best_match {
-            let did = match &input.subject {
-                Union::Refs(InputSubjectRefs::ComAtprotoAdminDefsRepoRef(r)) => Some(r.did.as_str()),
-                Union::Refs(InputSubjectRefs::ComAtprotoRepoStrongRefMain(r)) => match atrium_api::types::string::Did::new(r.uri.clone()) {
-                     Ok(_) => Some(r.uri.as_str()),
-                     _ => if r.uri.starts_with("at://") {
-                         r.uri.split('/').nth(2)
-                     } else {
-                         None
-                     }
-                },
-                _ => None,
-            };
+        },
+    };
 
-            if let Some(did_str) = did {
-                tracing::info!(val, did = did_str, "Gimmick Triggered! Forcing fortune");
-                if let Err(e) = overwrite_fortune(
-                    did_str,
-                    val,
-                    &state.pool,
-                    &state.keypair,
-                    &config().labeler_did,
-                    &state.tx
-                ).await {
-                    tracing::error!(error = ?e, "Failed to overwrite fortune");
-                } else {
-                    tracing::info!("Fortune overwritten successfully");
+    let mut headers = HeaderMap::new();
+    if let Some(command) = command {
+        if let Some(did_str) = subject_did {
+            // Only the account itself, or an admin, may change a fortune
+            let is_admin = config().admin_dids.contains(&reporter);
+            if did_str != reporter && !is_admin {
+                tracing::warn!(id, ?command, did = did_str, reporter, "Gimmick: Reporter may not change this fortune");
+                record_action(&state, id, route, &format!("denied:{}", describe(command))).await?;
+                return Err(XrpcError::Forbidden("You can only change your own fortune".to_string()));
+            }
+            let mut charged_day = None;
+            if !is_admin && command.uses_quota() {
+                let day = today_jst().format("%Y-%m-%d").to_string();
+                let (granted, limit) = take_quota(&state.pool, &day, &reporter, did_str).await?;
+                if !granted {
+                    tracing::warn!(id, ?command, did = did_str, reporter, "Gimmick: Daily quota used up");
+                    record_action(&state, id, route, &format!("rate_limited:{}", describe(command))).await?;
+                    return Err(XrpcError::RateLimitExceeded("Daily fortune change limit reached, try again tomorrow".to_string(), limit));
                 }
-            } else {
-                tracing::warn!("Gimmick: Failed to extract DID from subject");
+                headers = limit.headers();
+                charged_day = Some(day);
             }
+            tracing::info!(id, ?command, did = did_str, reporter, "Gimmick Triggered!");
+            let action = match run_command(&state, did_str, command).await {
+                Ok(action) => action,
+                Err(e) => {
+                    // Taken up front so concurrent reports can't overrun it, so give it back
+                    if let Some(day) = &charged_day
+                        && let Err(refund) = refund_override_quota(&state.pool, day, &reporter, did_str).await
+                    {
+                        tracing::error!(id, error = ?refund, "Failed to refund quota of a failed command");
+                    }
+                    if e.is::<OptedOut>() {
+                        tracing::info!(id, ?command, did = did_str, "Gimmick: Subject has opted out");
+                        record_action(&state, id, route, &format!("opted_out:{}", describe(command))).await?;
+                        return Err(XrpcError::Forbidden("This account has opted out of fortunes; opt back in first".to_string()));
+                    }
+                    return Err(e.into());
+                }
+            };
+            record_action(&state, id, route, &action).await?;
+            tracing::info!(id, action, "Gimmick command applied");
         } else {
-            tracing::debug!(reason, "Gimmick: No matching fortune keyword found");
+            tracing::warn!(id, "Gimmick: Failed to extract DID from subject");
+            metrics().report_triggers.inc(&[route.name(), "none"]);
         }
-    } else {
-        tracing::debug!("Gimmick: No reason provided in report");
+    } else if route != ReportRoute::Review {
+        metrics().report_triggers.inc(&[route.name(), "none"]);
     }
 
-    let subject = match &input.subject {
-        Union::Refs(InputSubjectRefs::ComAtprotoAdminDefsRepoRef(r)) => OutputSubjectRefs::ComAtprotoAdminDefsRepoRef(r.clone()),
-        Union::Refs(InputSubjectRefs::ComAtprotoRepoStrongRefMain(r)) => OutputSubjectRefs::ComAtprotoRepoStrongRefMain(r.clone()),
-        _ => {
-            tracing::warn!("Unknown subject type received");
-            // Fallback to a dummy strongRef to avoid crashing
-            OutputSubjectRefs::ComAtprotoRepoStrongRefMain(Box::new(atrium_api::com::atproto::repo::strong_ref::Main {
-                data: MainData {
-                    cid: atrium_api::types::string::Cid::new(Cid::from_str("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi").expect("Invalid dummy CID")),
-                    uri: "at://did:plc:dummy/app.bsky.feed.post/3juv3456789".to_string(),
-                },
-                extra_data: Ipld::Null,
-            }))
-        },
+    let row = get_report(&state.pool, id).await?
+        .ok_or_else(|| anyhow::anyhow!("Report {} disappeared after insert", id))?;
+    Ok((headers, Json(report_output(row)?)))
+}
+
+/// Stores what was done about a report and counts it by route and kind of action.
+async fn record_action(state: &AppState, id: i64, route: ReportRoute, action: &str) -> anyhow::Result<()> {
+    set_report_action(&mut *state.pool.acquire().await?, id, action).await?;
+    let kind = action.split(':').next().unwrap_or(action);
+    metrics().report_triggers.inc(&[route.name(), kind]);
+    Ok(())
+}
+
+/// Takes one command from the quotas of the reporter and the subject for `day`, reporting whichever is closer to running out.
+async fn take_quota(pool: &DbPool, day: &str, reporter: &str, subject: &str) -> anyhow::Result<(bool, RateLimit)> {
+    let conf = config();
+    let usage = take_override_quota(pool, day, reporter, subject, conf.override_quota_per_reporter, conf.override_quota_per_subject).await?;
+
+    let reporter_left = (conf.override_quota_per_reporter - usage.reporter_used).max(0);
+    let subject_left = (conf.override_quota_per_subject - usage.subject_used).max(0);
+    let (limit, remaining) = if reporter_left <= subject_left {
+        (conf.override_quota_per_reporter, reporter_left)
+    } else {
+        (conf.override_quota_per_subject, subject_left)
     };
+    Ok((usage.granted, RateLimit { limit, remaining, reset: next_day_jst_timestamp() }))
+}
+
+/// How a command is written in the `action` column of a report.
+fn describe(command: Command) -> String {
+    match command {
+        Command::Set(fortune) => format!("set:{}", fortune),
+        other => other.name().to_string(),
+    }
+}
+
+/// Applies a command to `did` and returns the action to record.
+async fn run_command(state: &AppState, did: &str, command: Command) -> anyhow::Result<String> {
+    let labeler_did = &config().labeler_did;
+    match command {
+        Command::Set(fortune) => {
+            overwrite_fortune(did, fortune.as_str(), &state.pool, &state.keypair, labeler_did, &state.tx).await?;
+        }
+        Command::Reroll => {
+            let fortune = random_fortune();
+            overwrite_fortune(did, fortune.as_str(), &state.pool, &state.keypair, labeler_did, &state.tx).await?;
+            return Ok(format!("reroll:{}", fortune));
+        }
+        Command::Reveal => {
+            assign_fortune(did, None, &state.pool, &state.keypair, labeler_did, &state.tx).await?;
+        }
+        Command::OptIn => {
+            opt_in(did, &state.pool, &state.keypair, labeler_did, &state.tx).await?;
+        }
+        Command::OptOut => {
+            opt_out(did, "report", &state.pool, &state.keypair, labeler_did, &state.tx).await?;
+        }
+        Command::Restore => {
+            let fortune = restore_daily_fortune(did, &state.pool, &state.keypair, labeler_did, &state.tx).await?;
+            return Ok(format!("restore:{}", fortune));
+        }
+    }
+    Ok(describe(command))
+}
 
-    let now = chrono::Utc::now().with_timezone(&FixedOffset::east_opt(0).unwrap());
-    Json(OutputData {
-        created_at: Datetime::new(now),
-        id: 12345, // Dummy ID
-        reason: input.reason.clone(),
-        reason_type: input.reason_type.clone(),
-        reported_by: Did::new("did:plc:unknown".to_string()).unwrap(),
-        subject: Union::Refs(subject),
+/// Builds the createReport response from the stored report.
+fn report_output(row: ReportRow) -> anyhow::Result<Output> {
+    let subject: Union<OutputSubjectRefs> = serde_json::from_str(&row.subject)?;
+    Ok(OutputData {
+        created_at: Datetime::from_str(&row.created_at).map_err(|e| anyhow::anyhow!("Invalid created_at: {:?}", e))?,
+        id: row.id,
+        reason: row.reason,
+        reason_type: row.reason_type,
+        reported_by: Did::new(row.reported_by).map_err(|e| anyhow::anyhow!("Invalid reporter DID: {}", e))?,
+        subject,
     }.into())
 }
@@ -1,76 +1,412 @@
 use axum::{
-    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, State},
+    extract::{ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, State},
     response::Response,
 };
-use atrium_api::com::atproto::label::subscribe_labels::{Labels, LabelsData};
+use atrium_api::com::atproto::label::subscribe_labels::{Info, InfoData, Labels, LabelsData};
 use atrium_api::com::atproto::label::defs::Label;
-use tokio::sync::broadcast;
 use serde::{Deserialize, Serialize};
-use crate::api::AppState;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use crate::api::{AppState, QsQuery};
+use crate::config::config;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+use crate::db::{get_label_event_bounds, get_label_events_after};
+use crate::domain::labeling::label_from_event;
+use crate::metrics::metrics;
 use ipld_core::ipld::Ipld;
 
+/// Number of stored frames fetched per page while replaying from a cursor.
+const REPLAY_PAGE_SIZE: i64 = 500;
+
+/// Query parameters of `com.atproto.label.subscribeLabels`.
+///
+/// The atrium `Parameters` type flattens its fields, which stops the `i64` cursor from parsing out of a query string.
+#[derive(Deserialize, Debug)]
+pub struct SubscribeParams {
+    cursor: Option<i64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct StreamHeader {
-    t: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    t: Option<String>,
     op: i64,
 }
 
+/// Body of an `op: -1` error frame.
+#[derive(Serialize, Deserialize, Debug)]
+struct ErrorFrame {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CursorStatus {
+    /// Cursor is within (or at the edge of) retained history.
+    Current,
+    /// Cursor is older than the oldest retained event; replay starts from the oldest one.
+    Outdated,
+    /// Cursor is ahead of the newest event we have ever emitted.
+    Future,
+}
+
+fn classify_cursor(cursor: i64, bounds: Option<(i64, i64)>) -> CursorStatus {
+    let (oldest, latest) = bounds.unwrap_or((0, 0));
+    if cursor > latest {
+        CursorStatus::Future
+    } else if bounds.is_some() && cursor + 1 < oldest {
+        CursorStatus::Outdated
+    } else {
+        CursorStatus::Current
+    }
+}
+
+/// Connection liveness settings for a single subscriber.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    ping_interval: Duration,
+    idle_timeout: Duration,
+}
+
 pub async fn subscribe_labels(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
+    QsQuery(params): QsQuery<SubscribeParams>,
 ) -> Response {
-    tracing::info!("WS: subscribeLabels request received");
-    ws.on_upgrade(move |socket| handle_socket(socket, state.tx))
+    let cursor = params.cursor;
+    let conf = config();
+    let heartbeat = Heartbeat {
+        ping_interval: Duration::from_secs(conf.ws_ping_interval_secs),
+        idle_timeout: Duration::from_secs(conf.ws_idle_timeout_secs),
+    };
+    tracing::info!(?cursor, "WS: subscribeLabels request received");
+    ws.on_upgrade(move |socket| handle_socket(socket, state, cursor, heartbeat))
 }
 
-async fn handle_socket(mut socket: WebSocket, tx: broadcast::Sender<(i64, Vec<Label>)>) {
+async fn handle_socket(mut socket: WebSocket, state: AppState, cursor: Option<i64>, heartbeat: Heartbeat) {
     tracing::info!("WS: Connection established");
-    let mut rx = tx.subscribe();
+    let _subscriber = metrics().stream_subscribers.track();
+    let mut shutdown = state.shutdown.subscribe();
+
+    // Highest seq delivered to this client; lag recovery resumes from here.
+    let mut last_seq = 0;
+    if cursor.is_none() {
+        // Live-only subscribers start from whatever is newest right now.
+        match get_label_event_bounds(&state.pool).await {
+            Ok(bounds) => last_seq = bounds.map(|(_, latest)| latest).unwrap_or(0),
+            Err(e) => {
+                tracing::error!(error = ?e, "WS: Failed to read event log bounds");
+                close_with_error(&mut socket, close_code::ERROR, "InternalError", None).await;
+                return;
+            }
+        }
+    }
+
+    // Subscribe before replaying so nothing emitted during the replay is missed.
+    let mut rx = state.tx.subscribe();
+
+    if let Some(cursor) = cursor {
+        let bounds = match get_label_event_bounds(&state.pool).await {
+            Ok(b) => b,
+            Err(e) => {
+                tracing::error!(error = ?e, "WS: Failed to read event log bounds");
+                close_with_error(&mut socket, close_code::ERROR, "InternalError", None).await;
+                return;
+            }
+        };
+
+        match classify_cursor(cursor, bounds) {
+            CursorStatus::Future => {
+                tracing::info!(cursor, ?bounds, "WS: Rejecting future cursor");
+                close_with_error(&mut socket, close_code::POLICY, "FutureCursor", Some("Cursor in the future.")).await;
+                return;
+            }
+            CursorStatus::Outdated => {
+                tracing::info!(cursor, ?bounds, "WS: Cursor older than retained history");
+                if let Err(e) = send_info(&mut socket, "OutdatedCursor", Some("Requested cursor exceeded limit. Possibly missing events")).await {
+                    tracing::warn!(error = ?e, "WS: Failed to send info frame");
+                    return;
+                }
+            }
+            CursorStatus::Current => {}
+        }
+
+        match replay(&mut socket, &state, cursor).await {
+            Ok((seq, count)) => {
+                last_seq = seq;
+                tracing::info!(cursor, last_seq, count, "WS: Replay complete, switching to live stream");
+            }
+            Err(e) => {
+                tracing::warn!(error = ?e, cursor, "WS: Replay failed");
+                return;
+            }
+        }
+    }
+
+    let mut ping = tokio::time::interval(heartbeat.ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ping.tick().await; // The first tick completes immediately
+    let mut last_heard = Instant::now();
 
     loop {
-        match rx.recv().await {
-            Ok((seq, labels)) => {
-                tracing::debug!(seq, count = labels.len(), "WS: Received broadcast");
-                // Construct Header
-                let header = StreamHeader {
-                    t: "#labels".to_string(),
-                    op: 1, // Frame
-                };
-
-                // Construct Body
-                let body = Labels {
-                    data: LabelsData {
-                        seq,
-                        labels: labels.clone(),
-                    },
-                    extra_data: Ipld::Null,
-                };
-
-                // Serialize
-                match (serde_ipld_dagcbor::to_vec(&header), serde_ipld_dagcbor::to_vec(&body)) {
-                    (Ok(header_bytes), Ok(body_bytes)) => {
-                        // Combine [Header][Body]
-                        let mut payload = header_bytes;
-                        payload.extend(body_bytes);
-
-                        // Send
-                        if let Err(e) = socket.send(Message::Binary(payload)).await {
-                            tracing::warn!(error = ?e, "WS: Failed to send message");
-                            break;
-                        } else {
-                            tracing::debug!("WS: Sent message to client");
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(frame))) => {
+                    tracing::info!(?frame, "WS: Client closed connection");
+                    break;
+                }
+                Some(Ok(_)) => {
+                    // Pongs and anything else the client sends prove the connection is alive
+                    last_heard = Instant::now();
+                }
+                Some(Err(e)) => {
+                    tracing::debug!(error = ?e, "WS: Failed to read from client");
+                    break;
+                }
+                None => {
+                    tracing::debug!("WS: Client disconnected");
+                    break;
+                }
+            },
+            _ = ping.tick() => {
+                if last_heard.elapsed() >= heartbeat.idle_timeout {
+                    tracing::info!(idle = ?last_heard.elapsed(), "WS: Closing idle connection");
+                    let _ = socket.send(Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "Idle timeout".into(),
+                    }))).await;
+                    break;
+                }
+                if let Err(e) = socket.send(Message::Ping(Vec::new())).await {
+                    tracing::debug!(error = ?e, "WS: Failed to send ping");
+                    break;
+                }
+            },
+            _ = wait_for_shutdown(&mut shutdown) => {
+                tracing::debug!("WS: Server shutting down");
+                let _ = socket.send(Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server shutting down".into(),
+                }))).await;
+                break;
+            },
+            received = rx.recv() => match received {
+                Ok((seq, labels)) => {
+                    // Already delivered by the replay
+                    if seq <= last_seq {
+                        tracing::debug!(seq, last_seq, "WS: Skipping frame already replayed");
+                        continue;
+                    }
+                    tracing::debug!(seq, count = labels.len(), "WS: Received broadcast");
+
+                    match encode_labels_frame(seq, labels) {
+                        Ok(payload) => {
+                            if let Err(e) = socket.send(Message::Binary(payload)).await {
+                                tracing::warn!(error = ?e, "WS: Failed to send message");
+                                metrics().frames_failed.inc();
+                                break;
+                            } else {
+                                metrics().frames_sent.inc();
+                                last_seq = seq;
+                                tracing::debug!("WS: Sent message to client");
+                            }
+                        }
+                        Err(e) => {
+                             tracing::error!(error = ?e, "Failed to serialize label update");
+                             metrics().frames_failed.inc();
                         }
                     }
-                    _ => {
-                         tracing::error!("Failed to serialize label update");
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    // Frames dropped from the buffer are still in the event log; catch up from there.
+                    tracing::warn!(skipped, last_seq, "WS: Consumer fell behind the broadcast buffer, catching up from store");
+                    metrics().broadcast_lagged.inc();
+                    match replay(&mut socket, &state, last_seq).await {
+                        Ok((seq, count)) => {
+                            tracing::info!(skipped, from = last_seq, to = seq, count, "WS: Caught up after lag");
+                            last_seq = seq;
+                        }
+                        Err(e) => {
+                            tracing::warn!(error = ?e, last_seq, "WS: Catch-up failed");
+                            close_with_error(&mut socket, close_code::POLICY, "ConsumerTooSlow", Some("Stream consumer too slow")).await;
+                            break;
+                        }
                     }
                 }
+                Err(RecvError::Closed) => {
+                    tracing::debug!("WS: Broadcast channel closed");
+                    let _ = socket.send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Server shutting down".into(),
+                    }))).await;
+                    break;
+                }
+            },
+        }
+    }
+    tracing::info!("WS: Connection closed");
+}
+
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stopping| *stopping).await;
+}
+
+/// Sends every stored frame after `cursor` in order and returns the last seq sent and how many frames were sent.
+async fn replay(socket: &mut WebSocket, state: &AppState, cursor: i64) -> anyhow::Result<(i64, usize)> {
+    let mut last_seq = cursor;
+    let mut count = 0;
+
+    loop {
+        let rows = get_label_events_after(&state.pool, last_seq, REPLAY_PAGE_SIZE).await?;
+
+        // Rows arrive as whole frames; regroup them by frame seq
+        let mut frames: Vec<(i64, Vec<Label>)> = Vec::new();
+        for row in rows {
+            let frame_seq = row.frame_seq();
+            let label = label_from_event(row)?;
+            match frames.last_mut() {
+                Some((seq, labels)) if *seq == frame_seq => labels.push(label),
+                _ => frames.push((frame_seq, vec![label])),
             }
-            Err(e) => {
-                tracing::debug!(error = ?e, "WS: Broadcast channel closed or lagged");
-                break;
+        }
+        let page_len = frames.len() as i64;
+
+        for (seq, labels) in frames {
+            let sent = match encode_labels_frame(seq, labels) {
+                Ok(payload) => socket.send(Message::Binary(payload)).await.map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                metrics().frames_failed.inc();
+                return Err(e);
             }
+            metrics().frames_sent.inc();
+            last_seq = seq;
+            count += 1;
+        }
+
+        if page_len < REPLAY_PAGE_SIZE {
+            break;
         }
     }
-    tracing::info!("WS: Connection closed");
+
+    Ok((last_seq, count))
+}
+
+/// Sends an `op: -1` error frame followed by a close frame.
+async fn close_with_error(socket: &mut WebSocket, code: u16, error: &str, message: Option<&str>) {
+    match encode_error_frame(error, message) {
+        Ok(payload) => {
+            if let Err(e) = socket.send(Message::Binary(payload)).await {
+                tracing::debug!(error = ?e, "WS: Failed to send error frame");
+            }
+        }
+        Err(e) => tracing::error!(error = ?e, "Failed to serialize error frame"),
+    }
+
+    let _ = socket.send(Message::Close(Some(CloseFrame {
+        code,
+        reason: error.to_string().into(),
+    }))).await;
+}
+
+async fn send_info(socket: &mut WebSocket, name: &str, message: Option<&str>) -> anyhow::Result<()> {
+    let payload = encode_info_frame(name, message)?;
+    socket.send(Message::Binary(payload)).await?;
+    Ok(())
+}
+
+/// Encodes a `#labels` message as `[Header][Body]` DAG-CBOR.
+fn encode_labels_frame(seq: i64, labels: Vec<Label>) -> anyhow::Result<Vec<u8>> {
+    let header = StreamHeader {
+        t: Some("#labels".to_string()),
+        op: 1, // Frame
+    };
+
+    let body = Labels {
+        data: LabelsData {
+            seq,
+            labels,
+        },
+        extra_data: Ipld::Null,
+    };
+
+    encode_frame(&header, &body)
+}
+
+fn encode_info_frame(name: &str, message: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    let header = StreamHeader {
+        t: Some("#info".to_string()),
+        op: 1,
+    };
+
+    let body = Info {
+        data: InfoData {
+            name: name.to_string(),
+            message: message.map(str::to_string),
+        },
+        extra_data: Ipld::Null,
+    };
+
+    encode_frame(&header, &body)
+}
+
+fn encode_error_frame(error: &str, message: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    let header = StreamHeader {
+        t: None,
+        op: -1, // Error
+    };
+
+    let body = ErrorFrame {
+        error: error.to_string(),
+        message: message.map(str::to_string),
+    };
+
+    encode_frame(&header, &body)
+}
+
+fn encode_frame<B: Serialize>(header: &StreamHeader, body: &B) -> anyhow::Result<Vec<u8>> {
+    let mut payload = serde_ipld_dagcbor::to_vec(header)?;
+    payload.extend(serde_ipld_dagcbor::to_vec(body)?);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_cursor() {
+        assert_eq!(classify_cursor(0, None), CursorStatus::Current);
+        assert_eq!(classify_cursor(1, None), CursorStatus::Future);
+
+        let bounds = Some((10, 20));
+        assert_eq!(classify_cursor(9, bounds), CursorStatus::Current);
+        assert_eq!(classify_cursor(20, bounds), CursorStatus::Current);
+        assert_eq!(classify_cursor(21, bounds), CursorStatus::Future);
+        assert_eq!(classify_cursor(3, bounds), CursorStatus::Outdated);
+    }
+
+    #[test]
+    fn test_error_frame_structure() {
+        let bytes = encode_error_frame("FutureCursor", None).unwrap();
+
+        // {"op": -1} followed by {"error": "FutureCursor"}
+        let expected_hex = "a1626f7020a1656572726f726c467574757265437572736f72";
+        assert_eq!(hex::encode(bytes), expected_hex);
+    }
+
+    #[test]
+    fn test_info_frame_structure() {
+        let bytes = encode_info_frame("OutdatedCursor", None).unwrap();
+        let mut de = serde_ipld_dagcbor::de::Deserializer::from_slice(&bytes);
+        let header: StreamHeader = serde::Deserialize::deserialize(&mut de).unwrap();
+        let body: InfoData = serde::Deserialize::deserialize(&mut de).unwrap();
+
+        assert_eq!(header.t.as_deref(), Some("#info"));
+        assert_eq!(header.op, 1);
+        assert_eq!(body.name, "OutdatedCursor");
+    }
 }
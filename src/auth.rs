@@ -0,0 +1,351 @@
+//! Inter-service auth: the JWTs a PDS attaches to requests it proxies to the labeler.
+use anyhow::Result;
+use atrium_crypto::{did::{format_did_key, parse_did_key, parse_multikey}, verify::Verifier, Algorithm};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a DID document lookup may take before the request is refused.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a resolved key is reused, so a burst of reports from one account resolves it once.
+const KEY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Looks up the key an account signs service auth tokens with.
+#[async_trait]
+pub trait DidResolver: Send + Sync {
+    /// Returns the `#atproto` signing key of `did`, formatted as a `did:key`.
+    async fn resolve_signing_key(&self, did: &str) -> Result<String>;
+}
+
+/// Resolves `did:plc` through a PLC directory and `did:web` through the host's `/.well-known/did.json`.
+pub struct HttpDidResolver {
+    plc_url: String,
+    client: reqwest::Client,
+    keys: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl HttpDidResolver {
+    pub fn new(plc_url: &str) -> Self {
+        Self::with_timeout(plc_url, RESOLVE_TIMEOUT)
+    }
+
+    pub fn with_timeout(plc_url: &str, timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self {
+            plc_url: plc_url.trim_end_matches('/').to_string(),
+            client,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached_key(&self, did: &str) -> Option<String> {
+        let keys = self.keys.lock().unwrap();
+        keys.get(did)
+            .filter(|(_, resolved_at)| resolved_at.elapsed() < KEY_CACHE_TTL)
+            .map(|(key, _)| key.clone())
+    }
+
+    fn cache_key(&self, did: &str, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, (_, resolved_at)| resolved_at.elapsed() < KEY_CACHE_TTL);
+        keys.insert(did.to_string(), (key.to_string(), Instant::now()));
+    }
+
+    fn document_url(&self, did: &str) -> Result<String> {
+        if did.starts_with("did:plc:") {
+            Ok(format!("{}/{}", self.plc_url, did))
+        } else if let Some(host) = did.strip_prefix("did:web:") {
+            Ok(format!("https://{}/.well-known/did.json", host.replace("%3A", ":")))
+        } else {
+            Err(anyhow::anyhow!("Unsupported DID method: {}", did))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidDocument {
+    id: String,
+    #[serde(default)]
+    verification_method: Vec<VerificationMethod>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerificationMethod {
+    id: String,
+    public_key_multibase: Option<String>,
+}
+
+#[async_trait]
+impl DidResolver for HttpDidResolver {
+    async fn resolve_signing_key(&self, did: &str) -> Result<String> {
+        if let Some(key) = self.cached_key(did) {
+            return Ok(key);
+        }
+
+        let doc: DidDocument = self.client.get(self.document_url(did)?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if doc.id != did {
+            return Err(anyhow::anyhow!("DID document is for {}, not {}", doc.id, did));
+        }
+
+        let multikey = doc.verification_method.iter()
+            .find(|m| m.id == "#atproto" || m.id == format!("{}#atproto", did))
+            .and_then(|m| m.public_key_multibase.as_deref())
+            .ok_or_else(|| anyhow::anyhow!("No #atproto signing key in DID document of {}", did))?;
+        let (alg, key) = parse_multikey(multikey)?;
+        let did_key = format_did_key(alg, &key)?;
+        self.cache_key(did, &did_key);
+        Ok(did_key)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Authorization header with a bearer token is required")]
+    Missing,
+    #[error("Malformed service auth token")]
+    Malformed,
+    #[error("Unsupported token algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("Token audience does not match this labeler")]
+    BadAudience,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token is not valid for this method")]
+    BadLexiconMethod,
+    #[error("Could not resolve signing key of token issuer")]
+    UnresolvedIssuer,
+    #[error("Could not reach the directory to resolve the token issuer")]
+    ResolverUnavailable,
+    #[error("Token signature is invalid")]
+    BadSignature,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct JwtPayload {
+    iss: String,
+    aud: String,
+    exp: i64,
+    lxm: Option<String>,
+}
+
+/// Verifies a service auth token for a call to `lxm` and returns the issuing DID.
+pub async fn verify_service_jwt(
+    token: &str,
+    audience: &str,
+    lxm: &str,
+    resolver: &dyn DidResolver,
+) -> Result<String, AuthError> {
+    let mut parts = token.split('.');
+    let (Some(header_b64), Some(payload_b64), Some(sig_b64), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(AuthError::Malformed);
+    };
+    let header: JwtHeader = decode_part(header_b64)?;
+    let payload: JwtPayload = decode_part(payload_b64)?;
+    let sig = URL_SAFE_NO_PAD.decode(sig_b64).map_err(|_| AuthError::Malformed)?;
+
+    let alg = match header.alg.as_str() {
+        "ES256K" => Algorithm::Secp256k1,
+        "ES256" => Algorithm::P256,
+        other => return Err(AuthError::UnsupportedAlgorithm(other.to_string())),
+    };
+    // PDSes proxying to the labeler service may address it as `did:plc:xyz#atproto_labeler`
+    if payload.aud != audience && payload.aud != format!("{}#atproto_labeler", audience) {
+        return Err(AuthError::BadAudience);
+    }
+    if payload.exp <= chrono::Utc::now().timestamp() {
+        return Err(AuthError::Expired);
+    }
+    if payload.lxm.as_deref() != Some(lxm) {
+        return Err(AuthError::BadLexiconMethod);
+    }
+
+    // The issuer may name a service of the account, e.g. `did:plc:xyz#atproto_labeler`
+    let iss = payload.iss.split('#').next().unwrap_or_default().to_string();
+    let did_key = resolver.resolve_signing_key(&iss).await.map_err(|e| {
+        tracing::warn!(error = ?e, iss, "Failed to resolve service auth issuer");
+        // Our failure to look the issuer up says nothing about the token
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error()) => AuthError::ResolverUnavailable,
+            _ => AuthError::UnresolvedIssuer,
+        }
+    })?;
+    let (key_alg, key) = parse_did_key(&did_key).map_err(|_| AuthError::UnresolvedIssuer)?;
+    if key_alg != alg {
+        return Err(AuthError::BadSignature);
+    }
+
+    // PDSes may produce high-S signatures, which the reference implementation accepts for JWTs
+    let signed = &token[..header_b64.len() + 1 + payload_b64.len()];
+    Verifier::new(true)
+        .verify(alg, &key, signed.as_bytes(), &sig)
+        .map_err(|_| AuthError::BadSignature)?;
+
+    Ok(iss)
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, AuthError> {
+    let bytes = URL_SAFE_NO_PAD.decode(part).map_err(|_| AuthError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| AuthError::Malformed)
+}
+
+/// A resolver backed by a fixed map, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct StaticDidResolver(pub std::collections::HashMap<String, String>);
+
+#[cfg(test)]
+#[async_trait]
+impl DidResolver for StaticDidResolver {
+    async fn resolve_signing_key(&self, did: &str) -> Result<String> {
+        self.0.get(did).cloned().ok_or_else(|| anyhow::anyhow!("Unknown DID: {}", did))
+    }
+}
+
+/// Signs a service auth token the way a PDS would, for tests.
+#[cfg(test)]
+pub fn sign_service_jwt(keypair: &atrium_crypto::keypair::Secp256k1Keypair, iss: &str, aud: &str, lxm: &str, exp: i64) -> String {
+    let header = URL_SAFE_NO_PAD.encode(serde_json::json!({ "typ": "JWT", "alg": "ES256K" }).to_string());
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::json!({ "iss": iss, "aud": aud, "exp": exp, "lxm": lxm }).to_string());
+    let signed = format!("{}.{}", header, payload);
+    let sig = keypair.sign(signed.as_bytes()).unwrap();
+    format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(sig))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atrium_crypto::keypair::{Did, Secp256k1Keypair};
+    use rand::rngs::OsRng;
+
+    const LXM: &str = "com.atproto.moderation.createReport";
+
+    fn resolver_for(did: &str, keypair: &Secp256k1Keypair) -> StaticDidResolver {
+        let mut resolver = StaticDidResolver::default();
+        resolver.0.insert(did.to_string(), keypair.did());
+        resolver
+    }
+
+    #[tokio::test]
+    async fn test_verify_service_jwt() {
+        let keypair = Secp256k1Keypair::create(&mut OsRng);
+        let resolver = resolver_for("did:plc:alice", &keypair);
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:labeler", LXM, exp);
+        assert_eq!(verify_service_jwt(&token, "did:plc:labeler", LXM, &resolver).await.unwrap(), "did:plc:alice");
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice#atproto_labeler", "did:plc:labeler", LXM, exp);
+        assert_eq!(verify_service_jwt(&token, "did:plc:labeler", LXM, &resolver).await.unwrap(), "did:plc:alice");
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:labeler#atproto_labeler", LXM, exp);
+        assert_eq!(verify_service_jwt(&token, "did:plc:labeler", LXM, &resolver).await.unwrap(), "did:plc:alice");
+    }
+
+    #[tokio::test]
+    async fn test_verify_service_jwt_rejections() {
+        let keypair = Secp256k1Keypair::create(&mut OsRng);
+        let resolver = resolver_for("did:plc:alice", &keypair);
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let verify = |token: String| {
+            let resolver = &resolver;
+            async move { verify_service_jwt(&token, "did:plc:labeler", LXM, resolver).await }
+        };
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:other", LXM, exp);
+        assert!(matches!(verify(token).await, Err(AuthError::BadAudience)));
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:labeler#atproto_pds", LXM, exp);
+        assert!(matches!(verify(token).await, Err(AuthError::BadAudience)));
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:labeler", LXM, exp - 120);
+        assert!(matches!(verify(token).await, Err(AuthError::Expired)));
+
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:labeler", "com.atproto.label.queryLabels", exp);
+        assert!(matches!(verify(token).await, Err(AuthError::BadLexiconMethod)));
+
+        let token = sign_service_jwt(&keypair, "did:plc:mallory", "did:plc:labeler", LXM, exp);
+        assert!(matches!(verify(token).await, Err(AuthError::UnresolvedIssuer)));
+
+        // Signed by a key that isn't the issuer's
+        let other = Secp256k1Keypair::create(&mut OsRng);
+        let token = sign_service_jwt(&other, "did:plc:alice", "did:plc:labeler", LXM, exp);
+        assert!(matches!(verify(token).await, Err(AuthError::BadSignature)));
+
+        assert!(matches!(verify("not-a-jwt".to_string()).await, Err(AuthError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn test_http_resolver_reads_atproto_key() {
+        let keypair = Secp256k1Keypair::create(&mut OsRng);
+        let did_key = keypair.did();
+        let doc = serde_json::json!({
+            "id": "did:plc:alice",
+            "verificationMethod": [{
+                "id": "did:plc:alice#atproto",
+                "type": "Multikey",
+                "controller": "did:plc:alice",
+                "publicKeyMultibase": did_key.trim_start_matches("did:key:"),
+            }],
+        });
+
+        // A stand-in PLC directory
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        let app = axum::Router::new().route("/:did", axum::routing::get(move |axum::extract::Path(did): axum::extract::Path<String>| async move {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if did == "did:plc:alice" {
+                Ok(axum::Json(doc))
+            } else {
+                Err(axum::http::StatusCode::NOT_FOUND)
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let resolver = HttpDidResolver::new(&format!("http://{}/", addr));
+        assert_eq!(resolver.resolve_signing_key("did:plc:alice").await.unwrap(), did_key);
+        assert!(resolver.resolve_signing_key("did:plc:bob").await.is_err());
+        assert!(resolver.resolve_signing_key("did:example:carol").await.is_err());
+
+        // Served from the cache the second time
+        assert_eq!(resolver.resolve_signing_key("did:plc:alice").await.unwrap(), did_key);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_http_resolver_times_out() {
+        // A PLC directory that never answers
+        let app = axum::Router::new().route("/:did", axum::routing::get(std::future::pending::<()>));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let resolver = HttpDidResolver::with_timeout(&format!("http://{}", addr), Duration::from_millis(200));
+        let keypair = Secp256k1Keypair::create(&mut OsRng);
+        let token = sign_service_jwt(&keypair, "did:plc:alice", "did:plc:labeler", LXM, chrono::Utc::now().timestamp() + 60);
+        let result = tokio::time::timeout(Duration::from_secs(5), verify_service_jwt(&token, "did:plc:labeler", LXM, &resolver)).await
+            .expect("Resolver should have given up");
+        assert!(matches!(result, Err(AuthError::ResolverUnavailable)));
+    }
+}
@@ -9,6 +9,7 @@ use atrium_api::com::atproto::label::defs::{
 use atrium_api::types::string::{Datetime, Language, Nsid, RecordKey};
 use atrium_api::types::Unknown;
 use atrium_xrpc_client::reqwest::ReqwestClient;
+use omikuji::api::report::REPORT_ROUTES;
 use omikuji::config::config;
 use std::str::FromStr;
 
@@ -26,7 +27,7 @@ async fn main() -> anyhow::Result<()> {
     println!("Adding label definitions...");
 
     let agent = AtpAgent::new(
-        ReqwestClient::new("https://bsky.social"),
+        ReqwestClient::new(&conf.service_url),
         MemorySessionStore::default(),
     );
 
@@ -71,7 +72,7 @@ async fn main() -> anyhow::Result<()> {
             label_value_definitions: Some(label_value_definitions),
         }
         .into(),
-        reason_types: None,
+        reason_types: Some(REPORT_ROUTES.iter().map(|(reason_type, _)| reason_type.to_string()).collect()),
         subject_collections: None,
         subject_types: None,
     };
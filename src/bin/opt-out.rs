@@ -0,0 +1,44 @@
+//! Manages the opt-out registry through the running labeler's admin API, so revocations reach subscribers.
+//!
+//! Usage: opt-out list | opt-out add <did> | opt-out remove <did>
+use omikuji::config::config;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let conf = config();
+    let token = conf
+        .admin_token
+        .as_ref()
+        .expect("ADMIN_TOKEN must be set in .env");
+    let base = std::env::var("LABELER_URL").unwrap_or_else(|_| format!("http://127.0.0.1:{}", conf.port));
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let client = reqwest::Client::new();
+    let request = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["list"] => client.get(format!("{}/admin/opt-outs", base)),
+        ["add", did] => client.put(format!("{}/admin/opt-outs/{}", base, did)),
+        ["remove", did] => client.delete(format!("{}/admin/opt-outs/{}", base, did)),
+        _ => {
+            eprintln!("Usage: opt-out list | opt-out add <did> | opt-out remove <did>");
+            std::process::exit(2);
+        }
+    };
+
+    let response = request.bearer_auth(token).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        // Not necessarily an XRPC error body, e.g. a proxy error page
+        anyhow::bail!("{}: {}", status, response.text().await.unwrap_or_default().trim());
+    }
+    let body: serde_json::Value = response.json().await?;
+
+    for entry in body["optOuts"].as_array().into_iter().flatten() {
+        println!(
+            "{}\t{}\t{}",
+            entry["did"].as_str().unwrap_or_default(),
+            entry["source"].as_str().unwrap_or_default(),
+            entry["createdAt"].as_str().unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! An in-memory stand-in for the labeler account, for driving the poller and batches in tests.
+use super::{BskyClient, Follower, Notification, Page};
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// An in-memory account for tests. Listings are kept newest first and paged by offset.
+#[derive(Default)]
+pub struct FakeBsky {
+    pub state: std::sync::Mutex<FakeBskyState>,
+}
+
+#[derive(Default)]
+pub struct FakeBskyState {
+    pub logged_in: bool,
+    pub logins: usize,
+    pub notifications: Vec<Notification>,
+    pub followers: Vec<Follower>,
+    pub handles: std::collections::HashMap<String, String>,
+    pub seen_at: Option<String>,
+    /// Returned, in order, by the next calls that need a session.
+    pub failures: std::collections::VecDeque<anyhow::Error>,
+}
+
+impl FakeBsky {
+    /// Adds a notification newer than every one before it.
+    pub fn notify(&self, reason: &str, did: &str, indexed_at: &str) {
+        self.state.lock().unwrap().notifications.insert(0, Notification {
+            reason: reason.to_string(),
+            author_did: did.to_string(),
+            author_handle: format!("{}.test", did.rsplit(':').next().unwrap_or(did)),
+            indexed_at: indexed_at.to_string(),
+        });
+    }
+
+    /// Makes every call fail like a PDS rejecting an expired token until the next login.
+    pub fn expire_session(&self) {
+        self.state.lock().unwrap().logged_in = false;
+    }
+
+    fn check(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(e) = state.failures.pop_front() {
+            return Err(e);
+        }
+        if !state.logged_in {
+            return Err(xrpc_error::<atrium_api::app::bsky::notification::list_notifications::Error>(400, "ExpiredToken").into());
+        }
+        Ok(())
+    }
+
+    fn page<T: Clone>(items: &[T], cursor: Option<String>, limit: u8) -> Result<Page<T>> {
+        let start = cursor.map(|c| c.parse::<usize>()).transpose()?.unwrap_or(0).min(items.len());
+        let end = (start + limit as usize).min(items.len());
+        Ok(Page {
+            items: items[start..end].to_vec(),
+            cursor: (end < items.len()).then(|| end.to_string()),
+        })
+    }
+}
+
+/// An XRPC error response as atrium reports it.
+pub fn xrpc_error<E: std::fmt::Debug>(status: u16, name: &str) -> atrium_api::xrpc::Error<E> {
+    use atrium_api::xrpc::error::{ErrorResponseBody, XrpcError, XrpcErrorKind};
+    atrium_api::xrpc::Error::XrpcResponse(XrpcError {
+        status: atrium_api::xrpc::http::StatusCode::from_u16(status).unwrap(),
+        error: Some(XrpcErrorKind::Undefined(ErrorResponseBody { error: Some(name.to_string()), message: None })),
+    })
+}
+
+#[async_trait]
+impl BskyClient for FakeBsky {
+    async fn login(&self) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        state.logged_in = true;
+        state.logins += 1;
+        Ok(true)
+    }
+
+    async fn has_session(&self) -> bool {
+        self.state.lock().unwrap().logged_in
+    }
+
+    async fn list_notifications(&self, cursor: Option<String>, limit: u8) -> Result<Page<Notification>> {
+        self.check()?;
+        Self::page(&self.state.lock().unwrap().notifications, cursor, limit)
+    }
+
+    async fn update_seen(&self, seen_at: &str) -> Result<()> {
+        self.check()?;
+        self.state.lock().unwrap().seen_at = Some(seen_at.to_string());
+        Ok(())
+    }
+
+    async fn list_followers(&self, _actor: &str, cursor: Option<String>, limit: u8) -> Result<Page<Follower>> {
+        self.check()?;
+        Self::page(&self.state.lock().unwrap().followers, cursor, limit)
+    }
+
+    async fn resolve_handle(&self, handle: &str) -> Result<String> {
+        self.state.lock().unwrap().handles.get(handle).cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown handle: {}", handle))
+    }
+}
@@ -0,0 +1,147 @@
+//! The labeler's own Bluesky account, shared by the poller and the scheduler.
+use anyhow::Result;
+use atrium_api::agent::atp_agent::store::MemorySessionStore;
+use atrium_api::agent::atp_agent::AtpAgent;
+use atrium_api::types::string::{AtIdentifier, Did, Handle};
+use atrium_xrpc_client::reqwest::ReqwestClient;
+use async_trait::async_trait;
+use crate::config::config;
+
+#[cfg(test)]
+pub mod fake;
+
+/// A notification of the labeler account, reduced to what the poller reads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// `follow`, `like`, `reply`, ...
+    pub reason: String,
+    pub author_did: String,
+    pub author_handle: String,
+    pub indexed_at: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Follower {
+    pub did: String,
+    pub handle: String,
+}
+
+/// One page of a listing, newest first, with the cursor of the next page if there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub cursor: Option<String>,
+}
+
+/// The calls the labeler makes as its own account.
+#[async_trait]
+pub trait BskyClient: Send + Sync {
+    /// Logs in with the configured credentials. Returns `false` when no password is configured.
+    async fn login(&self) -> Result<bool>;
+    async fn has_session(&self) -> bool;
+    async fn list_notifications(&self, cursor: Option<String>, limit: u8) -> Result<Page<Notification>>;
+    /// Marks notifications up to `seen_at` (RFC 3339) as read.
+    async fn update_seen(&self, seen_at: &str) -> Result<()>;
+    /// `actor` is a DID or a handle.
+    async fn list_followers(&self, actor: &str, cursor: Option<String>, limit: u8) -> Result<Page<Follower>>;
+    async fn resolve_handle(&self, handle: &str) -> Result<String>;
+}
+
+/// Logs in unless the client already holds a session. Returns `false` when no password is configured.
+pub async fn ensure_logged_in(client: &dyn BskyClient) -> Result<bool> {
+    if client.has_session().await {
+        return Ok(true);
+    }
+    client.login().await
+}
+
+/// Talks to the PDS at `service_url` through atrium.
+pub struct AtriumClient {
+    agent: AtpAgent<MemorySessionStore, ReqwestClient>,
+}
+
+impl AtriumClient {
+    pub fn new(service_url: &str) -> Self {
+        Self { agent: AtpAgent::new(ReqwestClient::new(service_url), MemorySessionStore::default()) }
+    }
+}
+
+#[async_trait]
+impl BskyClient for AtriumClient {
+    async fn login(&self) -> Result<bool> {
+        let conf = config();
+        let Some(password) = &conf.labeler_password else {
+            return Ok(false);
+        };
+        let identifier = conf.handle.as_deref().unwrap_or(&conf.labeler_did);
+        tracing::info!(identifier, service = conf.service_url, "Attempting login");
+        self.agent.login(identifier, password).await?;
+        tracing::info!("Bot logged in");
+        Ok(true)
+    }
+
+    async fn has_session(&self) -> bool {
+        self.agent.get_session().await.is_some()
+    }
+
+    async fn list_notifications(&self, cursor: Option<String>, limit: u8) -> Result<Page<Notification>> {
+        let resp = self.agent.api.app.bsky.notification.list_notifications(
+            atrium_api::app::bsky::notification::list_notifications::ParametersData {
+                cursor,
+                limit: Some(limit.try_into().map_err(|e| anyhow::anyhow!("Invalid limit: {}", e))?),
+                priority: None,
+                reasons: None,
+                seen_at: None,
+            }.into()
+        ).await?;
+        let items = resp.data.notifications.into_iter()
+            .map(|n| Notification {
+                reason: n.data.reason,
+                author_did: n.data.author.did.as_str().to_string(),
+                author_handle: n.data.author.handle.as_str().to_string(),
+                indexed_at: n.data.indexed_at.as_str().to_string(),
+            })
+            .collect();
+        Ok(Page { items, cursor: resp.data.cursor })
+    }
+
+    async fn update_seen(&self, seen_at: &str) -> Result<()> {
+        let dt = chrono::DateTime::parse_from_rfc3339(seen_at)?;
+        self.agent.api.app.bsky.notification.update_seen(
+            atrium_api::app::bsky::notification::update_seen::InputData {
+                seen_at: atrium_api::types::string::Datetime::new(dt),
+            }.into()
+        ).await?;
+        Ok(())
+    }
+
+    async fn list_followers(&self, actor: &str, cursor: Option<String>, limit: u8) -> Result<Page<Follower>> {
+        let actor = if actor.starts_with("did:") {
+            AtIdentifier::Did(Did::new(actor.to_string()).map_err(|e| anyhow::anyhow!("Invalid DID {}: {}", actor, e))?)
+        } else {
+            AtIdentifier::Handle(Handle::new(actor.to_string()).map_err(|e| anyhow::anyhow!("Invalid handle {}: {}", actor, e))?)
+        };
+        let resp = self.agent.api.app.bsky.graph.get_followers(
+            atrium_api::app::bsky::graph::get_followers::ParametersData {
+                actor,
+                cursor,
+                limit: Some(limit.try_into().map_err(|e| anyhow::anyhow!("Invalid limit: {}", e))?),
+            }.into()
+        ).await?;
+        let items = resp.data.followers.into_iter()
+            .map(|f| Follower {
+                did: f.did.as_str().to_string(),
+                handle: f.handle.as_str().to_string(),
+            })
+            .collect();
+        Ok(Page { items, cursor: resp.data.cursor })
+    }
+
+    async fn resolve_handle(&self, handle: &str) -> Result<String> {
+        let handle = Handle::new(handle.to_string()).map_err(|e| anyhow::anyhow!("Invalid handle {}: {}", handle, e))?;
+        let resp = self.agent.api.com.atproto.identity.resolve_handle(
+            atrium_api::com::atproto::identity::resolve_handle::ParametersData { handle }.into()
+        ).await?;
+        Ok(resp.data.did.as_str().to_string())
+    }
+}
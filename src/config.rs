@@ -10,6 +10,14 @@ pub struct Config {
     pub signing_key_hex: String, // Hex encoded private key
     pub labeler_password: Option<String>, // For generic bot login if needed? Or actually handling handle/password
     pub handle: Option<String>,
+    pub ws_ping_interval_secs: u64, // How often subscribeLabels sockets are pinged
+    pub ws_idle_timeout_secs: u64, // Close sockets that have sent nothing (not even a pong) for this long
+    pub service_url: String, // PDS of the labeler account, used for login, notifications and followers
+    pub plc_url: String, // PLC directory used to resolve service auth issuers
+    pub admin_dids: Vec<String>, // Accounts allowed to change anyone's fortune through reports
+    pub admin_token: Option<String>, // Bearer token for the /admin API; the API is disabled without one
+    pub override_quota_per_reporter: i64, // Report commands one account may run per JST day
+    pub override_quota_per_subject: i64, // Report commands that may target one account per JST day
 }
 
 pub fn config() -> &'static Config {
@@ -24,6 +32,29 @@ pub fn config() -> &'static Config {
             signing_key_hex: env::var("SIGNING_KEY").expect("SIGNING_KEY must be set"),
             labeler_password: env::var("LABELER_PASSWORD").ok(),
             handle: env::var("HANDLE").ok(), // Use this to authenticate for polling?
+            ws_ping_interval_secs: env::var("WS_PING_INTERVAL_SECS").unwrap_or_else(|_| "30".to_string()).parse().expect("WS_PING_INTERVAL_SECS must be a number"),
+            ws_idle_timeout_secs: env::var("WS_IDLE_TIMEOUT_SECS").unwrap_or_else(|_| "90".to_string()).parse().expect("WS_IDLE_TIMEOUT_SECS must be a number"),
+            service_url: env::var("SERVICE_URL").unwrap_or_else(|_| "https://bsky.social".to_string()),
+            plc_url: env::var("PLC_URL").unwrap_or_else(|_| "https://plc.directory".to_string()),
+            override_quota_per_reporter: env::var("OVERRIDE_QUOTA_PER_REPORTER").unwrap_or_else(|_| "5".to_string()).parse().expect("OVERRIDE_QUOTA_PER_REPORTER must be a number"),
+            override_quota_per_subject: env::var("OVERRIDE_QUOTA_PER_SUBJECT").unwrap_or_else(|_| "5".to_string()).parse().expect("OVERRIDE_QUOTA_PER_SUBJECT must be a number"),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            admin_dids: env::var("ADMIN_DIDS").unwrap_or_default().split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
         }
     })
 }
+
+/// Sets the environment every test expects and builds the config from it, once per test binary.
+#[cfg(test)]
+pub fn init_test_config() -> &'static Config {
+    static ENV: std::sync::Once = std::sync::Once::new();
+    ENV.call_once(|| unsafe {
+        env::set_var("LABELER_DID", "did:plc:test");
+        env::set_var("SIGNING_KEY", "0000000000000000000000000000000000000000000000000000000000000000");
+        env::set_var("ADMIN_DIDS", "did:plc:admin");
+        env::set_var("ADMIN_TOKEN", "test-admin-token");
+        env::set_var("OVERRIDE_QUOTA_PER_REPORTER", "10");
+        env::set_var("OVERRIDE_QUOTA_PER_SUBJECT", "3");
+    });
+    config()
+}
@@ -1,15 +1,16 @@
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use sqlx::{sqlite::SqlitePoolOptions, Pool, QueryBuilder, Sqlite, SqliteConnection, SqliteExecutor};
 use anyhow::Result;
 use std::fs;
 use std::path::Path;
+use crate::metrics::metrics;
 
 pub type DbPool = Pool<Sqlite>;
 
 pub async fn init_db(db_path: &str) -> Result<DbPool> {
-    if let Some(parent) = Path::new(db_path).parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
+    if let Some(parent) = Path::new(db_path).parent()
+        && !parent.exists()
+    {
+        fs::create_dir_all(parent)?;
     }
 
     let db_url = format!("sqlite:{}?mode=rwc", db_path);
@@ -44,15 +45,124 @@ pub async fn init_db(db_path: &str) -> Result<DbPool> {
         .execute(&pool)
         .await;
 
+    // Append-only log of every emitted label. `seq` is the stream sequence number.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS label_events (
+          seq INTEGER PRIMARY KEY AUTOINCREMENT,
+          uri TEXT NOT NULL,
+          val TEXT NOT NULL,
+          neg INTEGER NOT NULL DEFAULT 0,
+          cts TEXT NOT NULL,
+          exp TEXT,
+          sig BLOB NOT NULL,
+          src TEXT NOT NULL
+        );
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_label_events_uri_val ON label_events (uri, val, seq)")
+        .execute(&pool)
+        .await?;
+
+    // Labels written together share one stream frame, identified by the last seq of the batch.
+    // NULL means the row is a frame on its own.
+    let _ = sqlx::query("ALTER TABLE label_events ADD COLUMN batch_seq INTEGER")
+        .execute(&pool)
+        .await;
+
+    // Remaining signed fields, so labels can be served exactly as they were signed.
+    // Rows written before these existed were always signed with ver = 1 and no cid.
+    let _ = sqlx::query("ALTER TABLE label_events ADD COLUMN ver INTEGER")
+        .execute(&pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE label_events ADD COLUMN cid TEXT")
+        .execute(&pool)
+        .await;
+
+    // Every createReport received, with what was done about it.
+    // `subject` is the report subject as JSON; `subject_did` is the account it belongs to.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS reports (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          subject TEXT NOT NULL,
+          subject_did TEXT,
+          reason_type TEXT NOT NULL,
+          reason TEXT,
+          reported_by TEXT NOT NULL,
+          created_at TEXT NOT NULL,
+          action TEXT
+        );
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    // Outcome of human review, for reports that needed one
+    let _ = sqlx::query("ALTER TABLE reports ADD COLUMN resolution TEXT")
+        .execute(&pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE reports ADD COLUMN note TEXT")
+        .execute(&pool)
+        .await;
+    let _ = sqlx::query("ALTER TABLE reports ADD COLUMN resolved_at TEXT")
+        .execute(&pool)
+        .await;
+
+    // Accounts that asked not to be labeled
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS opt_outs (
+          did TEXT PRIMARY KEY,
+          source TEXT NOT NULL,
+          created_at TEXT NOT NULL
+        );
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    // Progress of background workers that must survive restarts, e.g. the poller's notification high-water mark
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS poller_state (
+          key TEXT PRIMARY KEY,
+          value TEXT NOT NULL,
+          updated_at TEXT NOT NULL
+        );
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    // Commands run through reports per JST day, counted per reporter and per subject.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS override_quota (
+          day TEXT NOT NULL,
+          kind TEXT NOT NULL,
+          key TEXT NOT NULL,
+          used INTEGER NOT NULL DEFAULT 0,
+          PRIMARY KEY (day, kind, key)
+        );
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
     Ok(pool)
 }
 
-pub async fn upsert_label(pool: &DbPool, uri: &str, val: &str, cts: &str, neg: bool, src: &str, is_fixed: bool) -> Result<i64> {
+pub async fn upsert_label(conn: &mut SqliteConnection, uri: &str, val: &str, cts: &str, neg: bool, src: &str, is_fixed: bool) -> Result<i64> {
+    let _timer = metrics().time_query("upsert_label");
     // Manually delete duplicates to ensure uniqueness on legacy schemas without explicit PK
     sqlx::query("DELETE FROM labels WHERE uri = ? AND val = ?")
         .bind(uri)
         .bind(val)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
 
     let neg_int = if neg { 1 } else { 0 };
@@ -64,18 +174,19 @@ pub async fn upsert_label(pool: &DbPool, uri: &str, val: &str, cts: &str, neg: b
         .bind(neg_int)
         .bind(src)
         .bind(fixed_int)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
     Ok(result.last_insert_rowid())
 }
 
-pub async fn delete_label(pool: &DbPool, uri: &str) -> Result<()> {
+pub async fn delete_label(conn: &mut SqliteConnection, uri: &str) -> Result<()> {
+    let _timer = metrics().time_query("delete_label");
     // Soft delete: Update is_deleted flag and update timestamp
     let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
     sqlx::query("UPDATE labels SET is_deleted = 1, cts = ? WHERE uri = ?")
         .bind(now)
         .bind(uri)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
     Ok(())
 }
@@ -93,6 +204,7 @@ pub struct LabelRow {
 }
 
 pub async fn get_labels(pool: &DbPool, uri: &str, cursor: Option<i64>, limit: Option<i64>) -> Result<Vec<LabelRow>> {
+    let _timer = metrics().time_query("get_labels");
     let limit = limit.unwrap_or(50);
     let cursor = cursor.unwrap_or(0);
 
@@ -107,10 +219,487 @@ pub async fn get_labels(pool: &DbPool, uri: &str, cursor: Option<i64>, limit: Op
     Ok(rows)
 }
 
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct LabelEventRow {
+    pub seq: i64,
+    pub uri: String,
+    pub val: String,
+    pub neg: i32,
+    pub cts: String,
+    pub exp: Option<String>,
+    pub sig: Vec<u8>,
+    pub src: String,
+    pub batch_seq: Option<i64>,
+    pub ver: Option<i64>,
+    pub cid: Option<String>,
+}
+
+impl LabelEventRow {
+    /// The seq of the stream frame this row was emitted in.
+    pub fn frame_seq(&self) -> i64 {
+        self.batch_seq.unwrap_or(self.seq)
+    }
+}
+
+/// The signed fields of a label as they are written to the event log.
+#[derive(Debug, Clone, Copy)]
+pub struct NewLabelEvent<'a> {
+    pub uri: &'a str,
+    pub val: &'a str,
+    pub neg: bool,
+    pub cts: &'a str,
+    pub exp: Option<&'a str>,
+    pub sig: &'a [u8],
+    pub src: &'a str,
+    pub ver: Option<i64>,
+    pub cid: Option<&'a str>,
+}
+
+pub async fn append_label_event(conn: &mut SqliteConnection, event: NewLabelEvent<'_>) -> Result<i64> {
+    let _timer = metrics().time_query("append_label_event");
+    let neg_int = if event.neg { 1 } else { 0 };
+    let result = sqlx::query("INSERT INTO label_events (uri, val, neg, cts, exp, sig, src, ver, cid) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(event.uri)
+        .bind(event.val)
+        .bind(neg_int)
+        .bind(event.cts)
+        .bind(event.exp)
+        .bind(event.sig)
+        .bind(event.src)
+        .bind(event.ver)
+        .bind(event.cid)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Groups the events `first..=last` into one frame whose seq is `last`.
+pub async fn set_label_batch(conn: &mut SqliteConnection, first: i64, last: i64) -> Result<()> {
+    let _timer = metrics().time_query("set_label_batch");
+    sqlx::query("UPDATE label_events SET batch_seq = ? WHERE seq BETWEEN ? AND ?")
+        .bind(last)
+        .bind(first)
+        .bind(last)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Returns the events of up to `limit` whole frames after `cursor`, oldest first, for stream replay.
+pub async fn get_label_events_after(pool: &DbPool, cursor: i64, limit: i64) -> Result<Vec<LabelEventRow>> {
+    let _timer = metrics().time_query("get_label_events_after");
+    // A batch occupies a contiguous seq range ending at its frame seq,
+    // so bounding by the last wanted frame never splits a batch.
+    let rows = sqlx::query_as::<_, LabelEventRow>(
+        r#"
+        SELECT seq, uri, val, neg, cts, exp, sig, src, batch_seq, ver, cid FROM label_events
+        WHERE seq > ? AND COALESCE(batch_seq, seq) <= (
+            SELECT MAX(frame) FROM (
+                SELECT COALESCE(batch_seq, seq) AS frame FROM label_events
+                WHERE seq > ? GROUP BY frame ORDER BY frame ASC LIMIT ?
+            )
+        )
+        ORDER BY seq ASC
+        "#
+    )
+        .bind(cursor)
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows)
+}
+
+/// Returns the oldest and newest seq in the event log, or `None` while it is empty.
+pub async fn get_label_event_bounds(pool: &DbPool) -> Result<Option<(i64, i64)>> {
+    let _timer = metrics().time_query("get_label_event_bounds");
+    let row: (Option<i64>, Option<i64>) = sqlx::query_as("SELECT MIN(seq), MAX(seq) FROM label_events")
+        .fetch_one(pool)
+        .await?;
+    Ok(row.0.zip(row.1))
+}
+
+/// A `uriPatterns` entry of `com.atproto.label.queryLabels`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriPattern {
+    /// Matches one URI exactly.
+    Exact(String),
+    /// A pattern ending in `*`; matches every URI starting with the text before it.
+    Prefix(String),
+}
+
+impl UriPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        match pattern.find('*') {
+            None => Ok(UriPattern::Exact(pattern.to_string())),
+            Some(pos) if pos == pattern.len() - 1 => Ok(UriPattern::Prefix(pattern[..pos].to_string())),
+            Some(_) => anyhow::bail!("Wildcard '*' is only allowed at the end of a uriPattern: {}", pattern),
+        }
+    }
+}
+
+/// Escapes `\`, `%` and `_` for a `LIKE ... ESCAPE '\'` clause.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Page size bounds of `com.atproto.label.queryLabels`.
+pub const QUERY_LIMIT_DEFAULT: i64 = 50;
+pub const QUERY_LIMIT_MAX: i64 = 250;
+
+/// Returns the latest event for each label value on URIs matching any of `patterns`,
+/// optionally restricted to labels issued by one of `sources`.
+/// Results are ordered by ascending seq, so the last row's seq is the cursor of the next page.
+pub async fn get_label_events(pool: &DbPool, patterns: &[UriPattern], sources: &[String], cursor: Option<i64>, limit: Option<i64>) -> Result<Vec<LabelEventRow>> {
+    let _timer = metrics().time_query("get_label_events");
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+    let limit = limit.unwrap_or(QUERY_LIMIT_DEFAULT).clamp(1, QUERY_LIMIT_MAX);
+    let cursor = cursor.unwrap_or(0);
+
+    let mut qb = QueryBuilder::<Sqlite>::new(
+        "SELECT seq, uri, val, neg, cts, exp, sig, src, batch_seq, ver, cid FROM label_events e WHERE ("
+    );
+    for (i, pattern) in patterns.iter().enumerate() {
+        if i > 0 {
+            qb.push(" OR ");
+        }
+        match pattern {
+            UriPattern::Exact(uri) => {
+                qb.push("uri = ").push_bind(uri.clone());
+            }
+            UriPattern::Prefix(prefix) => {
+                // LIKE ignores ASCII case, so also compare the prefix exactly
+                qb.push("(uri LIKE ").push_bind(format!("{}%", escape_like(prefix))).push(" ESCAPE '\\'");
+                qb.push(" AND substr(uri, 1, length(").push_bind(prefix.clone()).push(")) = ").push_bind(prefix.clone()).push(")");
+            }
+        }
+    }
+    qb.push(")");
+    if !sources.is_empty() {
+        qb.push(" AND src IN (");
+        let mut separated = qb.separated(", ");
+        for src in sources {
+            separated.push_bind(src.clone());
+        }
+        separated.push_unseparated(")");
+    }
+    qb.push(" AND seq > ").push_bind(cursor);
+    qb.push(" AND seq = (SELECT MAX(seq) FROM label_events WHERE uri = e.uri AND val = e.val)");
+    qb.push(" ORDER BY seq ASC LIMIT ").push_bind(limit);
+
+    let rows = qb.build_query_as::<LabelEventRow>()
+        .fetch_all(pool)
+        .await?;
+    Ok(rows)
+}
+
+/// Returns every event ever emitted for `uri`, newest first, starting below `cursor`.
+pub async fn get_label_history(pool: &DbPool, uri: &str, cursor: Option<i64>, limit: i64) -> Result<Vec<LabelEventRow>> {
+    let _timer = metrics().time_query("get_label_history");
+    let rows = sqlx::query_as::<_, LabelEventRow>(
+        "SELECT seq, uri, val, neg, cts, exp, sig, src, batch_seq, ver, cid FROM label_events WHERE uri = ? AND seq < ? ORDER BY seq DESC LIMIT ?"
+    )
+        .bind(uri)
+        .bind(cursor.unwrap_or(i64::MAX))
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows)
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct ReportRow {
+    pub id: i64,
+    pub subject: String,
+    pub subject_did: Option<String>,
+    pub reason_type: String,
+    pub reason: Option<String>,
+    pub reported_by: String,
+    pub created_at: String,
+    pub action: Option<String>,
+    pub resolution: Option<String>,
+    pub note: Option<String>,
+    pub resolved_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NewReport<'a> {
+    pub subject: &'a str,
+    pub subject_did: Option<&'a str>,
+    pub reason_type: &'a str,
+    pub reason: Option<&'a str>,
+    pub reported_by: &'a str,
+    pub created_at: &'a str,
+}
+
+pub async fn insert_report(conn: &mut SqliteConnection, report: NewReport<'_>) -> Result<i64> {
+    let _timer = metrics().time_query("insert_report");
+    let result = sqlx::query(
+        "INSERT INTO reports (subject, subject_did, reason_type, reason, reported_by, created_at) VALUES (?, ?, ?, ?, ?, ?)"
+    )
+        .bind(report.subject)
+        .bind(report.subject_did)
+        .bind(report.reason_type)
+        .bind(report.reason)
+        .bind(report.reported_by)
+        .bind(report.created_at)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Records what was done in response to a report.
+pub async fn set_report_action(conn: &mut SqliteConnection, id: i64, action: &str) -> Result<()> {
+    let _timer = metrics().time_query("set_report_action");
+    sqlx::query("UPDATE reports SET action = ? WHERE id = ?")
+        .bind(action)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_report(pool: &DbPool, id: i64) -> Result<Option<ReportRow>> {
+    let _timer = metrics().time_query("get_report");
+    let row = sqlx::query_as::<_, ReportRow>(
+        "SELECT id, subject, subject_did, reason_type, reason, reported_by, created_at, action, resolution, note, resolved_at FROM reports WHERE id = ?"
+    )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row)
+}
+
+/// Which reports to list for review.
+#[derive(Debug, Clone, Default)]
+pub struct ReportFilter {
+    /// `Some(false)` for reports still waiting for review, `Some(true)` for resolved ones.
+    pub resolved: Option<bool>,
+    /// Only reports that were queued for review rather than handled automatically.
+    pub queued_only: bool,
+    pub reason_type: Option<String>,
+    pub subject_did: Option<String>,
+    pub reported_by: Option<String>,
+}
+
+/// Lists reports oldest first, after `cursor` (a report id).
+pub async fn list_reports(pool: &DbPool, filter: &ReportFilter, cursor: Option<i64>, limit: i64) -> Result<Vec<ReportRow>> {
+    let _timer = metrics().time_query("list_reports");
+    let mut qb = QueryBuilder::<Sqlite>::new(
+        "SELECT id, subject, subject_did, reason_type, reason, reported_by, created_at, action, resolution, note, resolved_at FROM reports WHERE id > "
+    );
+    qb.push_bind(cursor.unwrap_or(0));
+    match filter.resolved {
+        Some(true) => { qb.push(" AND resolved_at IS NOT NULL"); }
+        Some(false) => { qb.push(" AND resolved_at IS NULL"); }
+        None => {}
+    }
+    if filter.queued_only {
+        qb.push(" AND action = 'queued'");
+    }
+    if let Some(reason_type) = &filter.reason_type {
+        qb.push(" AND reason_type = ").push_bind(reason_type.clone());
+    }
+    if let Some(subject_did) = &filter.subject_did {
+        qb.push(" AND subject_did = ").push_bind(subject_did.clone());
+    }
+    if let Some(reported_by) = &filter.reported_by {
+        qb.push(" AND reported_by = ").push_bind(reported_by.clone());
+    }
+    qb.push(" ORDER BY id ASC LIMIT ").push_bind(limit);
+
+    let rows = qb.build_query_as::<ReportRow>()
+        .fetch_all(pool)
+        .await?;
+    Ok(rows)
+}
+
+/// Marks a report as reviewed. Returns `false` if it was already resolved.
+pub async fn resolve_report(conn: &mut SqliteConnection, id: i64, resolution: &str, note: Option<&str>, resolved_at: &str) -> Result<bool> {
+    let _timer = metrics().time_query("resolve_report");
+    let result = sqlx::query("UPDATE reports SET resolution = ?, note = ?, resolved_at = ? WHERE id = ? AND resolved_at IS NULL")
+        .bind(resolution)
+        .bind(note)
+        .bind(resolved_at)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Undoes `resolve_report` when acting on the resolution failed, so the report can be resolved again.
+pub async fn reopen_report(conn: &mut SqliteConnection, id: i64, resolved_at: &str) -> Result<()> {
+    let _timer = metrics().time_query("reopen_report");
+    sqlx::query("UPDATE reports SET resolution = NULL, note = NULL, resolved_at = NULL WHERE id = ? AND resolved_at = ?")
+        .bind(id)
+        .bind(resolved_at)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OptOutRow {
+    pub did: String,
+    /// Where the opt-out came from: `report`, `admin`, ...
+    pub source: String,
+    pub created_at: String,
+}
+
+/// Adds `did` to the opt-out registry. Returns `false` if it was already there.
+pub async fn add_opt_out(conn: &mut SqliteConnection, did: &str, source: &str) -> Result<bool> {
+    let _timer = metrics().time_query("add_opt_out");
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let result = sqlx::query("INSERT INTO opt_outs (did, source, created_at) VALUES (?, ?, ?) ON CONFLICT (did) DO NOTHING")
+        .bind(did)
+        .bind(source)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Removes `did` from the opt-out registry. Returns `false` if it wasn't there.
+pub async fn remove_opt_out(conn: &mut SqliteConnection, did: &str) -> Result<bool> {
+    let _timer = metrics().time_query("remove_opt_out");
+    let result = sqlx::query("DELETE FROM opt_outs WHERE did = ?")
+        .bind(did)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn is_opted_out<'e>(conn: impl SqliteExecutor<'e>, did: &str) -> Result<bool> {
+    let _timer = metrics().time_query("is_opted_out");
+    let row = sqlx::query("SELECT 1 FROM opt_outs WHERE did = ?")
+        .bind(did)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+pub async fn list_opt_outs(pool: &DbPool) -> Result<Vec<OptOutRow>> {
+    let _timer = metrics().time_query("list_opt_outs");
+    let rows = sqlx::query_as::<_, OptOutRow>("SELECT did, source, created_at FROM opt_outs ORDER BY created_at ASC, did ASC")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows)
+}
+
+pub async fn get_poller_state(pool: &DbPool, key: &str) -> Result<Option<String>> {
+    let _timer = metrics().time_query("get_poller_state");
+    let value = sqlx::query_scalar("SELECT value FROM poller_state WHERE key = ?")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+    Ok(value)
+}
+
+pub async fn set_poller_state(conn: &mut SqliteConnection, key: &str, value: &str) -> Result<()> {
+    let _timer = metrics().time_query("set_poller_state");
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    sqlx::query("INSERT INTO poller_state (key, value, updated_at) VALUES (?, ?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at")
+        .bind(key)
+        .bind(value)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub granted: bool,
+    pub reporter_used: i64,
+    pub subject_used: i64,
+}
+
+/// Uses one override from both the reporter's and the subject's quota for `day`, unless either is used up.
+pub async fn take_override_quota(pool: &DbPool, day: &str, reporter: &str, subject: &str, reporter_limit: i64, subject_limit: i64) -> Result<QuotaUsage> {
+    let _timer = metrics().time_query("take_override_quota");
+    let mut tx = pool.begin().await?;
+
+    // Earlier days are never read again
+    sqlx::query("DELETE FROM override_quota WHERE day < ?")
+        .bind(day)
+        .execute(&mut *tx)
+        .await?;
+
+    // The conditional upsert runs under SQLite's write lock, so concurrent reports can't both take the last one
+    let granted = bump_quota(&mut tx, day, "reporter", reporter, reporter_limit).await?
+        && bump_quota(&mut tx, day, "subject", subject, subject_limit).await?;
+    if !granted {
+        tx.rollback().await?;
+        tx = pool.begin().await?;
+    }
+
+    let reporter_used = quota_used(&mut tx, day, "reporter", reporter).await?;
+    let subject_used = quota_used(&mut tx, day, "subject", subject).await?;
+    tx.commit().await?;
+
+    Ok(QuotaUsage { granted, reporter_used, subject_used })
+}
+
+/// Gives back an override taken by `take_override_quota` whose command then failed.
+pub async fn refund_override_quota(pool: &DbPool, day: &str, reporter: &str, subject: &str) -> Result<()> {
+    let _timer = metrics().time_query("refund_override_quota");
+    let mut tx = pool.begin().await?;
+    for (kind, key) in [("reporter", reporter), ("subject", subject)] {
+        sqlx::query("UPDATE override_quota SET used = used - 1 WHERE day = ? AND kind = ? AND key = ? AND used > 0")
+            .bind(day)
+            .bind(kind)
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn bump_quota(conn: &mut SqliteConnection, day: &str, kind: &str, key: &str, limit: i64) -> Result<bool> {
+    if limit <= 0 {
+        return Ok(false);
+    }
+    let result = sqlx::query(
+        "INSERT INTO override_quota (day, kind, key, used) VALUES (?, ?, ?, 1) ON CONFLICT (day, kind, key) DO UPDATE SET used = used + 1 WHERE used < ?"
+    )
+        .bind(day)
+        .bind(kind)
+        .bind(key)
+        .bind(limit)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+async fn quota_used(conn: &mut SqliteConnection, day: &str, kind: &str, key: &str) -> Result<i64> {
+    let used: Option<i64> = sqlx::query_scalar("SELECT used FROM override_quota WHERE day = ? AND kind = ? AND key = ?")
+        .bind(day)
+        .bind(kind)
+        .bind(key)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(used.unwrap_or(0))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn test_event<'a>(uri: &'a str, val: &'a str, neg: bool, cts: &'a str, sig: &'a [u8], src: &'a str) -> NewLabelEvent<'a> {
+        NewLabelEvent { uri, val, neg, cts, exp: None, sig, src, ver: Some(1), cid: None }
+    }
+
     #[tokio::test]
     async fn test_db_operations() -> Result<()> {
         let pool = init_db(":memory:").await?;
@@ -119,7 +708,7 @@ mod tests {
         let cts = "2026-01-01T00:00:00Z";
         let src = "did:plc:issuer";
 
-        upsert_label(&pool, uri, val, cts, false, src, false).await?;
+        upsert_label(&mut *pool.acquire().await?, uri, val, cts, false, src, false).await?;
 
         let labels = get_labels(&pool, uri, None, None).await?;
         assert_eq!(labels.len(), 1);
@@ -129,21 +718,303 @@ mod tests {
         assert_eq!(labels[0].is_fixed.unwrap_or(0), 0);
 
         let new_val = "chukichi";
-        upsert_label(&pool, uri, new_val, cts, false, src, true).await?;
+        upsert_label(&mut *pool.acquire().await?, uri, new_val, cts, false, src, true).await?;
 
         let labels_updated = get_labels(&pool, uri, None, None).await?;
         assert_eq!(labels_updated.len(), 2);
         assert_eq!(labels_updated[0].is_fixed.unwrap_or(0), 1);
 
         let neg_uri = "did:plc:negated";
-        upsert_label(&pool, neg_uri, "kyo", cts, true, src, false).await?;
+        upsert_label(&mut *pool.acquire().await?, neg_uri, "kyo", cts, true, src, false).await?;
         let items = get_labels(&pool, neg_uri, None, None).await?;
         assert_eq!(items[0].neg, 1);
 
-        delete_label(&pool, uri).await?;
+        delete_label(&mut *pool.acquire().await?, uri).await?;
         let empty = get_labels(&pool, uri, None, None).await?;
         assert_eq!(empty.len(), 0);
 
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_label_events_are_monotonic() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        assert_eq!(get_label_event_bounds(&pool).await?, None);
+        let cts = "2026-01-01T00:00:00.000Z";
+        let src = "did:plc:issuer";
+        let sig = [1u8, 2, 3];
+
+        let first = append_label_event(&mut *pool.acquire().await?, test_event("did:plc:a", "kichi", false, cts, &sig, src)).await?;
+        let second = append_label_event(&mut *pool.acquire().await?, test_event("did:plc:a", "kichi", true, cts, &sig, src)).await?;
+        let third = append_label_event(&mut *pool.acquire().await?, test_event("did:plc:b", "kyo", false, cts, &sig, src)).await?;
+        assert!(first < second && second < third);
+
+        let all = get_label_events_after(&pool, 0, 100).await?;
+        assert_eq!(all.iter().map(|r| r.seq).collect::<Vec<_>>(), vec![first, second, third]);
+        assert_eq!(all[0].sig, sig);
+        assert_eq!(all[0].cts, cts);
+
+        assert_eq!(get_label_event_bounds(&pool).await?, Some((first, third)));
+
+        let rest = get_label_events_after(&pool, first, 100).await?;
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[0].seq, second);
+
+        // Only the latest event per (uri, val) is current
+        let current = get_label_events(&pool, &[UriPattern::Exact("did:plc:a".to_string())], &[], None, None).await?;
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].seq, second);
+        assert_eq!(current[0].neg, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_label_event_batches_are_not_split() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let cts = "2026-01-01T00:00:00.000Z";
+        let src = "did:plc:issuer";
+        let sig = [1u8];
+
+        let single = append_label_event(&mut *pool.acquire().await?, test_event("did:plc:a", "kichi", false, cts, &sig, src)).await?;
+
+        let mut tx = pool.begin().await?;
+        let first = append_label_event(&mut tx, test_event("did:plc:b", "kyo", false, cts, &sig, src)).await?;
+        append_label_event(&mut tx, test_event("did:plc:b", "kichi", true, cts, &sig, src)).await?;
+        let last = append_label_event(&mut tx, test_event("did:plc:b", "daikichi", true, cts, &sig, src)).await?;
+        set_label_batch(&mut tx, first, last).await?;
+        tx.commit().await?;
+
+        let one_frame = get_label_events_after(&pool, 0, 1).await?;
+        assert_eq!(one_frame.len(), 1);
+        assert_eq!(one_frame[0].frame_seq(), single);
+
+        // The batch is returned whole even though it is 3 rows
+        let batch = get_label_events_after(&pool, single, 1).await?;
+        assert_eq!(batch.len(), 3);
+        assert!(batch.iter().all(|r| r.frame_seq() == last));
+
+        assert!(get_label_events_after(&pool, last, 10).await?.is_empty());
+        assert_eq!(get_label_event_bounds(&pool).await?, Some((single, last)));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_get_label_events_patterns_and_sources() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let cts = "2026-01-01T00:00:00.000Z";
+        let sig = [1u8];
+        let conn = &mut *pool.acquire().await?;
+
+        let src = "did:plc:issuer";
+        append_label_event(conn, test_event("at://did:plc:a/app.bsky.feed.post/1", "kichi", false, cts, &sig, src)).await?;
+        append_label_event(conn, test_event("at://did:plc:a/app.bsky.feed.post/2", "kyo", false, cts, &sig, src)).await?;
+        append_label_event(conn, test_event("at://did:plc:ab/app.bsky.feed.post/1", "kichi", false, cts, &sig, src)).await?;
+        append_label_event(conn, test_event("at://did:plc:a_c/app.bsky.feed.post/1", "kichi", false, cts, &sig, src)).await?;
+        let src = "did:plc:other";
+        append_label_event(conn, test_event("at://did:plc:a/app.bsky.feed.post/3", "kichi", false, cts, &sig, src)).await?;
+
+        let prefix = UriPattern::parse("at://did:plc:a/*")?;
+        assert_eq!(get_label_events(&pool, std::slice::from_ref(&prefix), &[], None, None).await?.len(), 3);
+
+        // `_` is literal, not a single-character wildcard
+        let underscore = UriPattern::parse("at://did:plc:a_*")?;
+        let rows = get_label_events(&pool, std::slice::from_ref(&underscore), &[], None, None).await?;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].uri, "at://did:plc:a_c/app.bsky.feed.post/1");
+
+        // Prefix matching is case-sensitive
+        let upper = UriPattern::parse("AT://did:plc:a/*")?;
+        assert!(get_label_events(&pool, std::slice::from_ref(&upper), &[], None, None).await?.is_empty());
+
+        let sources = vec!["did:plc:other".to_string()];
+        let rows = get_label_events(&pool, std::slice::from_ref(&prefix), &sources, None, None).await?;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].src, "did:plc:other");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uri_pattern_parse() {
+        assert_eq!(UriPattern::parse("did:plc:a").unwrap(), UriPattern::Exact("did:plc:a".to_string()));
+        assert_eq!(UriPattern::parse("at://did:plc:a/*").unwrap(), UriPattern::Prefix("at://did:plc:a/".to_string()));
+        assert_eq!(UriPattern::parse("*").unwrap(), UriPattern::Prefix(String::new()));
+        assert!(UriPattern::parse("at://*/app.bsky.feed.post/1").is_err());
+        assert!(UriPattern::parse("at://did:plc:a/**").is_err());
+    }
+    #[tokio::test]
+    async fn test_get_label_events_pages_across_patterns() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let cts = "2026-01-01T00:00:00.000Z";
+        let sig = [1u8];
+        let src = "did:plc:issuer";
+        let conn = &mut *pool.acquire().await?;
+
+        let mut expected = Vec::new();
+        for (i, uri) in ["did:plc:a", "did:plc:b", "did:plc:unrelated", "did:plc:a", "did:plc:b"].into_iter().enumerate() {
+            let val = format!("v{}", i);
+            let seq = append_label_event(conn, test_event(uri, &val, false, cts, &sig, src)).await?;
+            if uri != "did:plc:unrelated" {
+                expected.push(seq);
+            }
+        }
+
+        let patterns = vec![UriPattern::Exact("did:plc:a".to_string()), UriPattern::Exact("did:plc:b".to_string())];
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = get_label_events(&pool, &patterns, &[], cursor, Some(3)).await?;
+            assert!(page.len() <= 3);
+            let Some(last) = page.last() else { break };
+            cursor = Some(last.seq);
+            seen.extend(page.iter().map(|r| r.seq));
+        }
+        assert_eq!(seen, expected);
+
+        // Out-of-range limits are clamped rather than rejected
+        assert_eq!(get_label_events(&pool, &patterns, &[], None, Some(0)).await?.len(), 1);
+        assert_eq!(get_label_events(&pool, &patterns, &[], None, Some(1000)).await?.len(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reports_are_stored() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let report = NewReport {
+            subject: r#"{"$type":"com.atproto.admin.defs#repoRef","did":"did:plc:target"}"#,
+            subject_did: Some("did:plc:target"),
+            reason_type: "com.atproto.moderation.defs#reasonOther",
+            reason: Some("daikichi"),
+            reported_by: "did:plc:reporter",
+            created_at: "2026-01-01T00:00:00.000Z",
+        };
+
+        let first = insert_report(&mut *pool.acquire().await?, report).await?;
+        let second = insert_report(&mut *pool.acquire().await?, report).await?;
+        assert!(second > first);
+
+        set_report_action(&mut *pool.acquire().await?, first, "set:daikichi").await?;
+
+        let row = get_report(&pool, first).await?.expect("report stored");
+        assert_eq!(row.subject_did.as_deref(), Some("did:plc:target"));
+        assert_eq!(row.reported_by, "did:plc:reporter");
+        assert_eq!(row.action.as_deref(), Some("set:daikichi"));
+        assert!(get_report(&pool, second).await?.unwrap().action.is_none());
+        assert!(get_report(&pool, second + 1).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_override_quota() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let day = "2026-01-01";
+
+        let usage = take_override_quota(&pool, day, "did:plc:a", "did:plc:x", 2, 3).await?;
+        assert_eq!(usage, QuotaUsage { granted: true, reporter_used: 1, subject_used: 1 });
+        let usage = take_override_quota(&pool, day, "did:plc:a", "did:plc:y", 2, 3).await?;
+        assert_eq!(usage, QuotaUsage { granted: true, reporter_used: 2, subject_used: 1 });
+
+        // The reporter is out, and a refused override doesn't use the subject's quota either
+        let usage = take_override_quota(&pool, day, "did:plc:a", "did:plc:x", 2, 3).await?;
+        assert_eq!(usage, QuotaUsage { granted: false, reporter_used: 2, subject_used: 1 });
+
+        take_override_quota(&pool, day, "did:plc:b", "did:plc:x", 2, 3).await?;
+        take_override_quota(&pool, day, "did:plc:c", "did:plc:x", 2, 3).await?;
+        let usage = take_override_quota(&pool, day, "did:plc:d", "did:plc:x", 2, 3).await?;
+        assert_eq!(usage, QuotaUsage { granted: false, reporter_used: 0, subject_used: 3 });
+
+        // A new day starts over
+        let usage = take_override_quota(&pool, "2026-01-02", "did:plc:a", "did:plc:x", 2, 3).await?;
+        assert_eq!(usage, QuotaUsage { granted: true, reporter_used: 1, subject_used: 1 });
+
+        // A refund frees both again, and never goes below zero
+        refund_override_quota(&pool, "2026-01-02", "did:plc:a", "did:plc:x").await?;
+        refund_override_quota(&pool, "2026-01-02", "did:plc:a", "did:plc:x").await?;
+        let usage = take_override_quota(&pool, "2026-01-02", "did:plc:a", "did:plc:x", 2, 3).await?;
+        assert_eq!(usage, QuotaUsage { granted: true, reporter_used: 1, subject_used: 1 });
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_and_resolve_reports() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let report = |subject_did, reason_type| NewReport {
+            subject: "{}",
+            subject_did: Some(subject_did),
+            reason_type,
+            reason: None,
+            reported_by: "did:plc:reporter",
+            created_at: "2026-01-01T00:00:00.000Z",
+        };
+        let spam = "com.atproto.moderation.defs#reasonSpam";
+        let rude = "com.atproto.moderation.defs#reasonRude";
+
+        let mut ids = Vec::new();
+        for (did, reason_type) in [("did:plc:a", spam), ("did:plc:b", rude), ("did:plc:a", rude)] {
+            let id = insert_report(&mut *pool.acquire().await?, report(did, reason_type)).await?;
+            set_report_action(&mut *pool.acquire().await?, id, "queued").await?;
+            ids.push(id);
+        }
+        insert_report(&mut *pool.acquire().await?, report("did:plc:a", "com.atproto.moderation.defs#reasonOther")).await?;
+
+        let open = ReportFilter { resolved: Some(false), queued_only: true, ..Default::default() };
+        let listed: Vec<i64> = list_reports(&pool, &open, None, 50).await?.iter().map(|r| r.id).collect();
+        assert_eq!(listed, ids);
+
+        let filtered = ReportFilter { subject_did: Some("did:plc:a".to_string()), reason_type: Some(rude.to_string()), ..open.clone() };
+        let listed: Vec<i64> = list_reports(&pool, &filtered, None, 50).await?.iter().map(|r| r.id).collect();
+        assert_eq!(listed, vec![ids[2]]);
+
+        // Paging by id
+        let page = list_reports(&pool, &open, None, 2).await?;
+        let rest = list_reports(&pool, &open, Some(page[1].id), 2).await?;
+        assert_eq!(rest.iter().map(|r| r.id).collect::<Vec<_>>(), vec![ids[2]]);
+
+        assert!(resolve_report(&mut *pool.acquire().await?, ids[0], "dismiss", Some("not spam"), "2026-01-02T00:00:00.000Z").await?);
+        assert!(!resolve_report(&mut *pool.acquire().await?, ids[0], "negate", None, "2026-01-02T00:00:00.000Z").await?);
+
+        let row = get_report(&pool, ids[0]).await?.unwrap();
+        assert_eq!(row.resolution.as_deref(), Some("dismiss"));
+        assert_eq!(row.note.as_deref(), Some("not spam"));
+        assert_eq!(list_reports(&pool, &open, None, 50).await?.len(), 2);
+        let resolved = ReportFilter { resolved: Some(true), ..Default::default() };
+        assert_eq!(list_reports(&pool, &resolved, None, 50).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_opt_out_registry() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+
+        assert!(!is_opted_out(&pool, "did:plc:a").await?);
+        assert!(add_opt_out(&mut *pool.acquire().await?, "did:plc:a", "report").await?);
+        assert!(!add_opt_out(&mut *pool.acquire().await?, "did:plc:a", "admin").await?);
+        assert!(is_opted_out(&pool, "did:plc:a").await?);
+
+        let listed = list_opt_outs(&pool).await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].source, "report");
+
+        assert!(remove_opt_out(&mut *pool.acquire().await?, "did:plc:a").await?);
+        assert!(!remove_opt_out(&mut *pool.acquire().await?, "did:plc:a").await?);
+        assert!(!is_opted_out(&pool, "did:plc:a").await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_poller_state() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+
+        assert_eq!(get_poller_state(&pool, "last_seen_at").await?, None);
+        set_poller_state(&mut *pool.acquire().await?, "last_seen_at", "2026-01-01T00:00:00.000Z").await?;
+        set_poller_state(&mut *pool.acquire().await?, "last_seen_at", "2026-01-02T00:00:00.000Z").await?;
+        assert_eq!(get_poller_state(&pool, "last_seen_at").await?.as_deref(), Some("2026-01-02T00:00:00.000Z"));
+
         Ok(())
     }
 }
@@ -0,0 +1,326 @@
+//! Commands users can write in the reason of a report, in English or Japanese.
+use crate::domain::fortune::{Fortune, FORTUNES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Draw a new random fortune for today.
+    Reroll,
+    /// Label the account with today's fortune.
+    Reveal,
+    /// Stop labeling the account.
+    OptOut,
+    /// Start labeling the account again.
+    OptIn,
+    /// Force a specific fortune for today.
+    Set(Fortune),
+    /// Go back to the deterministic daily fortune. Requested by appeals rather than written in a reason.
+    Restore,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Reroll => "reroll",
+            Command::Reveal => "reveal",
+            Command::OptOut => "opt_out",
+            Command::OptIn => "opt_in",
+            Command::Set(_) => "set",
+            Command::Restore => "restore",
+        }
+    }
+
+    /// Whether the command counts against the daily quotas. Opting out must always work, and
+    /// opting in or revealing today's fortune doesn't override anything.
+    pub fn uses_quota(&self) -> bool {
+        !matches!(self, Command::OptOut | Command::OptIn | Command::Reveal)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verb {
+    Reroll,
+    Reveal,
+    OptOut,
+    OptIn,
+    Set,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Verb(Verb),
+    Fortune(Fortune),
+    /// Negates the next verb or fortune ("not daikichi").
+    NotNext,
+    /// Negates the previous verb or fortune ("大吉じゃない").
+    NotPrev,
+    Other,
+}
+
+/// Phrases other than the fortunes themselves. Words are space separated; Japanese is matched character by character.
+const PHRASES: &[(&str, Token)] = &[
+    ("reroll", Token::Verb(Verb::Reroll)),
+    ("re-roll", Token::Verb(Verb::Reroll)),
+    ("re roll", Token::Verb(Verb::Reroll)),
+    ("redraw", Token::Verb(Verb::Reroll)),
+    ("re-draw", Token::Verb(Verb::Reroll)),
+    ("roll again", Token::Verb(Verb::Reroll)),
+    ("draw again", Token::Verb(Verb::Reroll)),
+    ("引き直し", Token::Verb(Verb::Reroll)),
+    ("引き直す", Token::Verb(Verb::Reroll)),
+    ("引きなおし", Token::Verb(Verb::Reroll)),
+    ("引きなおす", Token::Verb(Verb::Reroll)),
+    ("もう一回", Token::Verb(Verb::Reroll)),
+    ("もう一度", Token::Verb(Verb::Reroll)),
+    ("再抽選", Token::Verb(Verb::Reroll)),
+    ("リロール", Token::Verb(Verb::Reroll)),
+    ("reveal", Token::Verb(Verb::Reveal)),
+    ("show", Token::Verb(Verb::Reveal)),
+    ("tell", Token::Verb(Verb::Reveal)),
+    ("教えて", Token::Verb(Verb::Reveal)),
+    ("見せて", Token::Verb(Verb::Reveal)),
+    ("占って", Token::Verb(Verb::Reveal)),
+    ("opt out", Token::Verb(Verb::OptOut)),
+    ("opt-out", Token::Verb(Verb::OptOut)),
+    ("optout", Token::Verb(Verb::OptOut)),
+    ("unsubscribe", Token::Verb(Verb::OptOut)),
+    ("オプトアウト", Token::Verb(Verb::OptOut)),
+    ("ラベル停止", Token::Verb(Verb::OptOut)),
+    ("ラベル不要", Token::Verb(Verb::OptOut)),
+    ("ラベルいらない", Token::Verb(Verb::OptOut)),
+    ("おみくじ停止", Token::Verb(Verb::OptOut)),
+    ("opt in", Token::Verb(Verb::OptIn)),
+    ("opt-in", Token::Verb(Verb::OptIn)),
+    ("optin", Token::Verb(Verb::OptIn)),
+    ("subscribe", Token::Verb(Verb::OptIn)),
+    ("resume", Token::Verb(Verb::OptIn)),
+    ("オプトイン", Token::Verb(Verb::OptIn)),
+    ("ラベル再開", Token::Verb(Verb::OptIn)),
+    ("おみくじ再開", Token::Verb(Verb::OptIn)),
+    ("再開", Token::Verb(Verb::OptIn)),
+    ("set", Token::Verb(Verb::Set)),
+    ("make", Token::Verb(Verb::Set)),
+    ("change", Token::Verb(Verb::Set)),
+    ("give", Token::Verb(Verb::Set)),
+    ("にして", Token::Verb(Verb::Set)),
+    ("に変更", Token::Verb(Verb::Set)),
+    ("に変えて", Token::Verb(Verb::Set)),
+    ("設定", Token::Verb(Verb::Set)),
+    ("not", Token::NotNext),
+    ("no", Token::NotNext),
+    ("don't", Token::NotNext),
+    ("dont", Token::NotNext),
+    ("never", Token::NotNext),
+    ("except", Token::NotNext),
+    ("without", Token::NotNext),
+    ("じゃない", Token::NotPrev),
+    ("じゃなく", Token::NotPrev),
+    ("ではない", Token::NotPrev),
+    ("ではなく", Token::NotPrev),
+    ("以外", Token::NotPrev),
+    ("いらない", Token::NotPrev),
+    ("不要", Token::NotPrev),
+];
+
+/// Japanese punctuation that separates words like whitespace does.
+const SEPARATORS: &str = "、。，．！？「」『』（）【】・…～〜：；　";
+
+/// Particles and copulas that may sit right next to a bare "吉" or "凶", as in "今日は凶に変更".
+const PARTICLES: &str = "はがをにでとものへやかよねだ";
+
+/// Splits text into matching units: lowercased ASCII words, and single characters of everything else.
+fn units(text: &str) -> Vec<String> {
+    split(text).0
+}
+
+/// `units`, along with whether whitespace or punctuation came right before each unit.
+fn split(text: &str) -> (Vec<String>, Vec<bool>) {
+    fn flush(word: &mut String, units: &mut Vec<String>, breaks: &mut Vec<bool>, broken: &mut bool) {
+        let trimmed = word.trim_end_matches(['-', '\'']);
+        if !trimmed.is_empty() {
+            units.push(trimmed.to_string());
+            breaks.push(std::mem::take(broken));
+        }
+        word.clear();
+    }
+
+    let mut units = Vec::new();
+    let mut breaks = Vec::new();
+    let mut broken = false;
+    let mut word = String::new();
+    for c in text.chars() {
+        let c = if c == '’' { '\'' } else { c };
+        if c.is_ascii_alphanumeric() || (!word.is_empty() && (c == '-' || c == '\'')) {
+            word.push(c.to_ascii_lowercase());
+            continue;
+        }
+        flush(&mut word, &mut units, &mut breaks, &mut broken);
+        if c.is_whitespace() || c.is_ascii_punctuation() || SEPARATORS.contains(c) {
+            broken = true;
+        } else {
+            units.push(c.to_string());
+            breaks.push(std::mem::take(&mut broken));
+        }
+    }
+    flush(&mut word, &mut units, &mut breaks, &mut broken);
+    (units, breaks)
+}
+
+fn vocabulary() -> Vec<(Vec<String>, Token)> {
+    let fortunes = FORTUNES.iter().flat_map(|f| [
+        (units(f.val.as_str()), Token::Fortune(f.val)),
+        (units(f.label), Token::Fortune(f.val)),
+    ]);
+    PHRASES.iter()
+        .map(|(phrase, token)| (units(phrase), *token))
+        .chain(fortunes)
+        .collect()
+}
+
+/// Always takes the longest phrase at each position, so "吉" is never found inside "大吉".
+fn tokenize(text: &str) -> Vec<Token> {
+    let (units, breaks) = split(text);
+    let vocabulary = vocabulary();
+    let longest_at = |i: usize| vocabulary.iter()
+        .filter(|(phrase, _)| units[i..].starts_with(phrase))
+        .max_by_key(|(phrase, _)| phrase.len());
+
+    // A lone "吉" or "凶" is also the start or end of many ordinary words ("吉田", "凶暴"), so it only
+    // counts as a fortune with a boundary, a particle or a command phrase on both sides
+    let is_edge = |i: usize, next_to: usize| {
+        breaks.get(i).copied().unwrap_or(true)
+            || units.get(next_to).is_none_or(|u| PARTICLES.contains(u.as_str()))
+    };
+    let stands_alone = |i: usize| {
+        let before = i == 0 || is_edge(i, i - 1);
+        let after = is_edge(i + 1, i + 1)
+            || matches!(longest_at(i + 1), Some((_, Token::Verb(_) | Token::NotPrev)));
+        before && after
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < units.len() {
+        match longest_at(i) {
+            Some((phrase, Token::Fortune(_))) if phrase.len() == 1 && !phrase[0].is_ascii() && !stands_alone(i) => {
+                tokens.push(Token::Other);
+                i += 1;
+            }
+            Some((phrase, token)) => {
+                tokens.push(*token);
+                i += phrase.len();
+            }
+            None => {
+                tokens.push(Token::Other);
+                i += 1;
+            }
+        }
+    }
+    tokens
+}
+
+/// Reads a command out of a report reason. Anything ambiguous yields `None`.
+pub fn parse_command(reason: &str) -> Option<Command> {
+    let tokens = tokenize(reason);
+    let is_word = |t: &Token| matches!(t, Token::Verb(_) | Token::Fortune(_));
+
+    let mut negated = vec![false; tokens.len()];
+    for (i, token) in tokens.iter().enumerate() {
+        let target = match token {
+            Token::NotNext => (i + 1..tokens.len()).find(|&j| is_word(&tokens[j])),
+            Token::NotPrev => (0..i).rev().find(|&j| is_word(&tokens[j])),
+            _ => None,
+        };
+        if let Some(j) = target {
+            negated[j] = true;
+        }
+    }
+
+    let mut verbs = Vec::new();
+    let mut fortunes = Vec::new();
+    for (token, negated) in tokens.iter().zip(negated) {
+        match token {
+            Token::Verb(v) if !negated && !verbs.contains(v) => verbs.push(*v),
+            Token::Fortune(f) if !negated && !fortunes.contains(f) => fortunes.push(*f),
+            _ => {}
+        }
+    }
+
+    match (verbs.as_slice(), fortunes.as_slice()) {
+        ([], [f]) | ([Verb::Set], [f]) => Some(Command::Set(*f)),
+        ([Verb::Reroll], []) => Some(Command::Reroll),
+        ([Verb::Reveal], []) => Some(Command::Reveal),
+        ([Verb::OptOut], []) => Some(Command::OptOut),
+        ([Verb::OptIn], []) => Some(Command::OptIn),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set() {
+        assert_eq!(parse_command("daikichi"), Some(Command::Set(Fortune::Daikichi)));
+        assert_eq!(parse_command("Set to Daikichi please!"), Some(Command::Set(Fortune::Daikichi)));
+        assert_eq!(parse_command("大吉にして"), Some(Command::Set(Fortune::Daikichi)));
+        assert_eq!(parse_command("吉"), Some(Command::Set(Fortune::Kichi)));
+        assert_eq!(parse_command("今日は凶に変更で"), Some(Command::Set(Fortune::Kyo)));
+        assert_eq!(parse_command("大凶"), Some(Command::Set(Fortune::Daikyo)));
+        // Repeating the same fortune is not ambiguous
+        assert_eq!(parse_command("大吉 daikichi"), Some(Command::Set(Fortune::Daikichi)));
+    }
+
+    #[test]
+    fn test_parse_does_not_find_fortunes_inside_words() {
+        // "吉" is part of "大吉", and "kichi" is part of "daikichi"
+        assert_eq!(parse_command("大吉"), Some(Command::Set(Fortune::Daikichi)));
+        assert_eq!(parse_command("suekichi"), Some(Command::Set(Fortune::Suekichi)));
+        assert_eq!(parse_command("不吉な投稿"), None);
+        assert_eq!(parse_command("凶悪なスパムです"), None);
+        assert_eq!(parse_command("kichijoji"), None);
+        assert_eq!(parse_command("吉田さんのスパムです"), None);
+        assert_eq!(parse_command("吉野の桜"), None);
+        assert_eq!(parse_command("凶暴な返信"), None);
+        assert_eq!(parse_command("山田吉"), None);
+        // Standing alone, or next to a particle or command
+        assert_eq!(parse_command("吉です"), Some(Command::Set(Fortune::Kichi)));
+        assert_eq!(parse_command("「凶」"), Some(Command::Set(Fortune::Kyo)));
+        assert_eq!(parse_command("凶 please"), Some(Command::Set(Fortune::Kyo)));
+    }
+
+    #[test]
+    fn test_parse_negation() {
+        assert_eq!(parse_command("not daikichi"), None);
+        assert_eq!(parse_command("I don't want daikichi"), None);
+        assert_eq!(parse_command("大吉じゃない"), None);
+        assert_eq!(parse_command("not daikichi, make it kyo"), Some(Command::Set(Fortune::Kyo)));
+        assert_eq!(parse_command("大吉以外の吉にして"), Some(Command::Set(Fortune::Kichi)));
+        assert_eq!(parse_command("don't reroll"), None);
+    }
+
+    #[test]
+    fn test_parse_verbs() {
+        assert_eq!(parse_command("reroll"), Some(Command::Reroll));
+        assert_eq!(parse_command("Re-roll my fortune"), Some(Command::Reroll));
+        assert_eq!(parse_command("おみくじを引き直したい"), Some(Command::Reroll));
+        assert_eq!(parse_command("もう一回！"), Some(Command::Reroll));
+        assert_eq!(parse_command("reveal"), Some(Command::Reveal));
+        assert_eq!(parse_command("今日の運勢を教えて"), Some(Command::Reveal));
+        assert_eq!(parse_command("please opt out"), Some(Command::OptOut));
+        assert_eq!(parse_command("オプトアウトします"), Some(Command::OptOut));
+        assert_eq!(parse_command("opt in"), Some(Command::OptIn));
+        assert_eq!(parse_command("ラベル再開して"), Some(Command::OptIn));
+    }
+
+    #[test]
+    fn test_parse_ambiguous() {
+        assert_eq!(parse_command(""), None);
+        assert_eq!(parse_command("this account posts spam"), None);
+        assert_eq!(parse_command("daikichi or kyo"), None);
+        assert_eq!(parse_command("大吉と凶"), None);
+        assert_eq!(parse_command("reroll to daikichi"), None);
+        assert_eq!(parse_command("opt out opt in"), None);
+        assert_eq!(parse_command("set"), None);
+    }
+}
@@ -1,5 +1,5 @@
 use sha2::{Sha256, Digest};
-use chrono::{Utc, FixedOffset};
+use chrono::{Utc, FixedOffset, NaiveDate};
 use std::fmt;
 use std::str::FromStr;
 
@@ -69,13 +69,24 @@ pub const FORTUNES: &[FortuneDef] = &[
 ];
 
 pub fn get_daily_fortune(did: &str) -> Fortune {
-    let jst_offset = FixedOffset::east_opt(9 * 3600).unwrap();
-    let now_jst = Utc::now().with_timezone(&jst_offset);
-    let date_str = now_jst.format("%Y-%m-%d").to_string();
+    let date_str = today_jst().format("%Y-%m-%d").to_string();
 
     calculate_fortune(did, &date_str)
 }
 
+/// Fortunes change at midnight in Japan.
+pub fn today_jst() -> NaiveDate {
+    let jst_offset = FixedOffset::east_opt(9 * 3600).unwrap();
+    Utc::now().with_timezone(&jst_offset).date_naive()
+}
+
+/// Unix time at which `today_jst()` moves on to the next day.
+pub fn next_day_jst_timestamp() -> i64 {
+    let jst_offset = FixedOffset::east_opt(9 * 3600).unwrap();
+    let tomorrow = today_jst().succ_opt().unwrap().and_hms_opt(0, 0, 0).unwrap();
+    tomorrow.and_local_timezone(jst_offset).unwrap().timestamp()
+}
+
 pub fn calculate_fortune(did: &str, date_str: &str) -> Fortune {
     let seed = format!("{}{}", did, date_str);
 
@@ -85,8 +96,15 @@ pub fn calculate_fortune(did: &str, date_str: &str) -> Fortune {
 
     // Read first 4 bytes as u32be
     let hash_val = u32::from_be_bytes(hash[0..4].try_into().unwrap());
-    let val = hash_val % 100;
+    fortune_for_roll(hash_val % 100)
+}
+
+/// Draws a fortune at random, with the same odds as the daily one.
+pub fn random_fortune() -> Fortune {
+    fortune_for_roll(rand::Rng::gen_range(&mut rand::thread_rng(), 0..100))
+}
 
+fn fortune_for_roll(val: u32) -> Fortune {
     for fortune in FORTUNES {
         if val < fortune.threshold {
             return fortune.val;
@@ -1,15 +1,23 @@
-use crate::db::{DbPool, upsert_label as db_upsert, delete_label as db_delete, get_labels as db_get_labels};
+use crate::db::{DbPool, LabelEventRow, NewLabelEvent, upsert_label as db_upsert, delete_label as db_delete, get_labels as db_get_labels, append_label_event, set_label_batch, add_opt_out, remove_opt_out, is_opted_out};
 use crate::domain::fortune::{get_daily_fortune, FORTUNES, Fortune};
 use std::str::FromStr;
 use crate::crypto::sign_label;
+use crate::metrics::metrics;
 use atrium_crypto::keypair::Secp256k1Keypair;
 use atrium_api::com::atproto::label::defs::{Label, LabelData};
 use atrium_api::types::string::{Datetime, Did};
 use chrono::Utc;
 use tracing;
 use anyhow::Result;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Mutex};
 
+/// A batch would have labeled an account that has opted out, so none of it was emitted.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} has opted out of labeling")]
+pub struct OptedOut(pub String);
+
+/// Labels `did` with today's fortune unless it was overridden manually today.
+/// Fails with `OptedOut` if the account has opted out.
 pub async fn assign_fortune(
     did: &str,
     handle: Option<&str>,
@@ -20,16 +28,16 @@ pub async fn assign_fortune(
 ) -> Result<()> {
     let current_labels = db_get_labels(pool, did, None, None).await?;
     let _now_str = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true); // Same format as in upsert
-    if let Some(fixed_label) = current_labels.iter().find(|l| l.is_fixed.unwrap_or(0) == 1 && l.neg == 0) {
-        if let Ok(fixed_date) = chrono::DateTime::parse_from_rfc3339(&fixed_label.cts) {
-            let now = Utc::now();
-            let trunc_fixed = fixed_date.with_timezone(&chrono::FixedOffset::east_opt(9*3600).unwrap()).date_naive();
-            let trunc_now = now.with_timezone(&chrono::FixedOffset::east_opt(9*3600).unwrap()).date_naive();
-
-            if trunc_fixed == trunc_now {
-                 tracing::info!(did, "Skipping assignment due to manual override (is_fixed=true)");
-                 return Ok(());
-            }
+    if let Some(fixed_label) = current_labels.iter().find(|l| l.is_fixed.unwrap_or(0) == 1 && l.neg == 0)
+        && let Ok(fixed_date) = chrono::DateTime::parse_from_rfc3339(&fixed_label.cts)
+    {
+        let now = Utc::now();
+        let trunc_fixed = fixed_date.with_timezone(&chrono::FixedOffset::east_opt(9*3600).unwrap()).date_naive();
+        let trunc_now = now.with_timezone(&chrono::FixedOffset::east_opt(9*3600).unwrap()).date_naive();
+
+        if trunc_fixed == trunc_now {
+             tracing::info!(did, "Skipping assignment due to manual override (is_fixed=true)");
+             return Ok(());
         }
     }
 
@@ -37,16 +45,7 @@ pub async fn assign_fortune(
     let handle_str = handle.unwrap_or("unknown");
     tracing::info!(did, handle = %handle_str, %fortune, "Processing user");
 
-    let negate_list: Vec<Fortune> = FORTUNES.iter()
-        .map(|f| f.val)
-        .filter(|&v| v != fortune)
-        .collect();
-
-    upsert_label(did, fortune.as_str(), false, labeler_did, pool, keypair, tx, false).await?;
-
-    for neg_fortune in negate_list {
-        upsert_label(did, neg_fortune.as_str(), true, labeler_did, pool, keypair, tx, false).await?;
-    }
+    apply_label_batch(did, &fortune_changes(fortune), false, labeler_did, pool, keypair, tx).await?;
 
     Ok(())
 }
@@ -65,18 +64,25 @@ pub async fn overwrite_fortune(
         Err(_) => return Err(anyhow::anyhow!("Invalid fortune value: {}", fortune_val)),
     };
 
-    let negate_list: Vec<Fortune> = FORTUNES.iter()
-        .map(|f| f.val)
-        .filter(|&v| v != fortune)
-        .collect();
-
-    upsert_label(did, fortune.as_str(), false, labeler_did, pool, keypair, tx, true).await?;
-    for neg_fortune in negate_list {
-        upsert_label(did, neg_fortune.as_str(), true, labeler_did, pool, keypair, tx, true).await?;
-    }
+    apply_label_batch(did, &fortune_changes(fortune), true, labeler_did, pool, keypair, tx).await?;
     Ok(())
 }
 
+/// Drops any manual override and labels `did` with its deterministic fortune for today.
+pub async fn restore_daily_fortune(
+    did: &str,
+    pool: &DbPool,
+    keypair: &Secp256k1Keypair,
+    labeler_did: &str,
+    tx: &broadcast::Sender<(i64, Vec<Label>)>
+) -> Result<Fortune> {
+    let fortune = get_daily_fortune(did);
+    tracing::info!(did, %fortune, "Restoring daily fortune");
+
+    apply_label_batch(did, &fortune_changes(fortune), false, labeler_did, pool, keypair, tx).await?;
+    Ok(fortune)
+}
+
 pub async fn revoke_fortune(
     did: &str,
     pool: &DbPool,
@@ -86,68 +92,106 @@ pub async fn revoke_fortune(
 ) -> Result<()> {
     // 1. Fetch current active labels
     let active_labels = db_get_labels(pool, did, None, None).await?;
-    let mut negation_labels = Vec::new();
-
-    let now_str = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
-    let cts = Datetime::from_str(&now_str).expect("Invalid timestamp");
 
+    // 2. Sign negations, only for positive labels to avoid redundancy
+    let mut negation_labels = Vec::new();
     for l in active_labels {
-        // Only negate positive labels to avoid redundancy
         if l.neg != 0 { continue; }
-
-        let mut label_data = LabelData {
-            cid: None,
-            cts: cts.clone(),
-            exp: None,
-            neg: Some(true),
-            sig: None,
-            src: Did::new(labeler_did.to_string()).expect("Invalid DID"),
-            uri: l.uri.clone(),
-            val: l.val.clone(),
-            ver: Some(1),
-        };
-
-        sign_label(&mut label_data, keypair)?;
-
-        negation_labels.push(Label {
-            data: label_data,
-            extra_data: ipld_core::ipld::Ipld::Null,
-        });
-    }
-
-    // 2. Broadcast negation labels
-    if !negation_labels.is_empty() {
-        // Use 0 as sequence number for revocation events as we don't produce new DB rows
-        match tx.send((0, negation_labels)) {
-            Ok(count) => tracing::info!(did, count, "Broadcasted negation labels"),
-            Err(_) => tracing::debug!(did, "No listeners active for negation broadcast"),
-        }
+        negation_labels.push(sign_new_label(&l.uri, &l.val, true, labeler_did, keypair)?);
     }
 
-    // 3. Soft Delete from DB
-    db_delete(pool, did).await?;
+    // 3. Record, broadcast and soft delete together
+    if negation_labels.is_empty() {
+        db_delete(&mut *pool.acquire().await?, did).await?;
+    } else {
+        let count = negation_labels.len();
+        commit_batch(pool, tx, negation_labels, StateUpdate::SoftDelete(did)).await?;
+        tracing::info!(did, count, "Emitted negation labels");
+    }
     tracing::info!(did, "Revoked fortune (Soft Delete complete)");
 
     Ok(())
 }
 
-async fn upsert_label(
+/// Stops labeling `did`: records the opt-out and revokes its current fortune right away.
+pub async fn opt_out(
+    did: &str,
+    source: &str,
+    pool: &DbPool,
+    keypair: &Secp256k1Keypair,
+    labeler_did: &str,
+    tx: &broadcast::Sender<(i64, Vec<Label>)>
+) -> Result<()> {
+    if add_opt_out(&mut *pool.acquire().await?, did, source).await? {
+        tracing::info!(did, source, "User opted out");
+    }
+    revoke_fortune(did, pool, keypair, labeler_did, tx).await
+}
+
+/// Starts labeling `did` again and gives it today's fortune.
+pub async fn opt_in(
+    did: &str,
+    pool: &DbPool,
+    keypair: &Secp256k1Keypair,
+    labeler_did: &str,
+    tx: &broadcast::Sender<(i64, Vec<Label>)>
+) -> Result<()> {
+    if remove_opt_out(&mut *pool.acquire().await?, did).await? {
+        tracing::info!(did, "User opted back in");
+    }
+    assign_fortune(did, None, pool, keypair, labeler_did, tx).await
+}
+
+/// One label value written for a subject as part of a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelChange<'a> {
+    pub val: &'a str,
+    pub neg: bool,
+}
+
+/// The positive label for `fortune` plus negations for every other fortune.
+fn fortune_changes(fortune: Fortune) -> Vec<LabelChange<'static>> {
+    FORTUNES.iter()
+        .map(|f| LabelChange { val: f.val.as_str(), neg: f.val != fortune })
+        .collect()
+}
+
+/// Writes all `changes` for `uri` in one transaction and emits them as a single frame.
+/// Returns the frame's seq, or fails with `OptedOut` if any change is positive and `uri` has opted out.
+pub async fn apply_label_batch(
     uri: &str,
-    val: &str,
-    neg: bool,
+    changes: &[LabelChange<'_>],
+    is_fixed: bool,
     src: &str,
     pool: &DbPool,
     keypair: &Secp256k1Keypair,
     tx: &broadcast::Sender<(i64, Vec<Label>)>,
-    is_fixed: bool
-) -> Result<()> {
-use std::str::FromStr;
+) -> Result<i64> {
+    let labels = changes.iter()
+        .map(|c| sign_new_label(uri, c.val, c.neg, src, keypair))
+        .collect::<Result<Vec<_>>>()?;
+
+    commit_batch(pool, tx, labels, StateUpdate::Upsert { is_fixed }).await
+}
+
+/// How a batch updates the current-state `labels` table alongside the event log.
+enum StateUpdate<'a> {
+    /// Record each label as the subject's current state.
+    Upsert { is_fixed: bool },
+    /// Soft delete every label of the subject.
+    SoftDelete(&'a str),
+    /// Only append to the event log.
+    None,
+}
+
+/// Builds and signs a label stamped with the current time.
+pub fn sign_new_label(uri: &str, val: &str, neg: bool, src: &str, keypair: &Secp256k1Keypair) -> Result<Label> {
     let now_str = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
     let cts = Datetime::from_str(&now_str).expect("Invalid timestamp");
 
     let mut label_data = LabelData {
         cid: None,
-        cts: cts.clone(),
+        cts,
         exp: None,
         neg: if neg { Some(true) } else { None },
         sig: None,
@@ -159,27 +203,140 @@ use std::str::FromStr;
 
     sign_label(&mut label_data, keypair)?;
 
-    let rowid = db_upsert(pool, uri, val, &cts.as_ref().to_rfc3339(), neg, src, is_fixed).await?;
-
-    // Create Label struct for broadcast
-    let label = Label {
-        data: label_data.clone(),
+    Ok(Label {
+        data: label_data,
         extra_data: ipld_core::ipld::Ipld::Null,
-    };
+    })
+}
+
+/// Serializes append + broadcast so frames reach subscribers in seq order.
+/// Subscribers skip anything at or below the last seq they delivered, so an out-of-order send would be lost.
+static EMIT_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Appends a signed label to the event log and broadcasts it under the new seq.
+pub async fn emit_label(
+    pool: &DbPool,
+    tx: &broadcast::Sender<(i64, Vec<Label>)>,
+    label: Label,
+) -> Result<i64> {
+    emit_labels(pool, tx, vec![label]).await
+}
+
+/// Appends signed labels to the event log as one batch and broadcasts them as a single frame.
+pub async fn emit_labels(
+    pool: &DbPool,
+    tx: &broadcast::Sender<(i64, Vec<Label>)>,
+    labels: Vec<Label>,
+) -> Result<i64> {
+    commit_batch(pool, tx, labels, StateUpdate::None).await
+}
+
+async fn commit_batch(
+    pool: &DbPool,
+    tx: &broadcast::Sender<(i64, Vec<Label>)>,
+    labels: Vec<Label>,
+    update: StateUpdate<'_>,
+) -> Result<i64> {
+    if labels.is_empty() {
+        anyhow::bail!("Cannot emit an empty label batch");
+    }
+
+    let _guard = EMIT_LOCK.lock().await;
+    let mut db_tx = pool.begin().await?;
 
-    // Broadcast
-    match tx.send((rowid, vec![label])) {
-        Ok(count) => tracing::debug!(listeners = count, seq = rowid, val, "Broadcaster sent label"),
-        Err(_) => tracing::debug!(seq = rowid, val, "Broadcaster: No listeners active"),
+    // Checked in the same transaction as the writes, so an opt-out committed before this batch always wins.
+    // Negations still go through: they are how an opt-out revokes.
+    for label in labels.iter().filter(|l| l.data.neg != Some(true)) {
+        if is_opted_out(&mut *db_tx, &label.data.uri).await? {
+            return Err(OptedOut(label.data.uri.clone()).into());
+        }
     }
 
-    Ok(())
+    let mut first_seq = None;
+    let mut last_seq = 0;
+    for label in &labels {
+        let data = &label.data;
+        let neg = data.neg.unwrap_or(false);
+        let sig = data.sig.as_deref().ok_or_else(|| anyhow::anyhow!("Label must be signed before emission"))?;
+
+        if let StateUpdate::Upsert { is_fixed } = update {
+            db_upsert(&mut db_tx, &data.uri, &data.val, data.cts.as_str(), neg, data.src.as_str(), is_fixed).await?;
+        }
+
+        let cid = data.cid.as_ref().map(|c| c.as_ref().to_string());
+        let seq = append_label_event(&mut db_tx, NewLabelEvent {
+            uri: &data.uri,
+            val: &data.val,
+            neg,
+            cts: data.cts.as_str(),
+            exp: data.exp.as_ref().map(|e| e.as_str()),
+            sig,
+            src: data.src.as_str(),
+            ver: data.ver,
+            cid: cid.as_deref(),
+        }).await?;
+        first_seq.get_or_insert(seq);
+        last_seq = seq;
+    }
+
+    if let Some(first_seq) = first_seq
+        && first_seq != last_seq
+    {
+        set_label_batch(&mut db_tx, first_seq, last_seq).await?;
+    }
+
+    if let StateUpdate::SoftDelete(uri) = update {
+        db_delete(&mut db_tx, uri).await?;
+    }
+
+    db_tx.commit().await?;
+
+    for label in &labels {
+        let neg = label.data.neg.unwrap_or(false);
+        metrics().labels_emitted.inc(&[&label.data.val, if neg { "true" } else { "false" }]);
+        if neg {
+            metrics().negations.inc();
+        }
+    }
+
+    let count = labels.len();
+    match tx.send((last_seq, labels)) {
+        Ok(listeners) => tracing::debug!(listeners, seq = last_seq, count, "Broadcaster sent labels"),
+        Err(_) => tracing::debug!(seq = last_seq, count, "Broadcaster: No listeners active"),
+    }
+
+    Ok(last_seq)
+}
+
+/// Rebuilds the signed label exactly as it was emitted, without re-signing.
+pub fn label_from_event(row: LabelEventRow) -> Result<Label> {
+    let cid = row.cid.as_deref()
+        .map(atrium_api::types::string::Cid::from_str)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Invalid cid: {}", e))?;
+
+    let label_data = LabelData {
+        cid,
+        cts: Datetime::from_str(&row.cts).map_err(|e| anyhow::anyhow!("Invalid cts {}: {}", row.cts, e))?,
+        exp: row.exp.as_deref().map(Datetime::from_str).transpose().map_err(|e| anyhow::anyhow!("Invalid exp: {}", e))?,
+        neg: if row.neg != 0 { Some(true) } else { None },
+        sig: Some(row.sig),
+        src: Did::new(row.src).map_err(|e| anyhow::anyhow!("Invalid src: {}", e))?,
+        uri: row.uri,
+        val: row.val,
+        ver: Some(row.ver.unwrap_or(1)),
+    };
+
+    Ok(Label {
+        data: label_data,
+        extra_data: ipld_core::ipld::Ipld::Null,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::{init_db, get_labels};
+    use crate::db::{init_db, get_labels, get_label_events_after};
 
     #[tokio::test]
     async fn test_assign_fortune_logic() -> Result<()> {
@@ -205,6 +362,103 @@ mod tests {
 
         println!("Granted fortune: {}", positives[0].val);
 
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_revoke_fortune_is_recorded() -> Result<()> {
+        use crate::db::get_label_events_after;
+        use atrium_crypto::keypair::Did as _;
+
+        let pool = init_db(":memory:").await?;
+        use rand::rngs::OsRng;
+        let mut rng = OsRng;
+        let keypair = Secp256k1Keypair::create(&mut rng);
+        let labeler_did = "did:plc:labeler";
+        let target_did = "did:plc:target";
+        let (tx, mut rx) = broadcast::channel(100);
+
+        assign_fortune(target_did, None, &pool, &keypair, labeler_did, &tx).await?;
+        revoke_fortune(target_did, &pool, &keypair, labeler_did, &tx).await?;
+
+        let events = get_label_events_after(&pool, 0, 100).await?;
+        assert_eq!(events.len(), 8, "7 assignments plus 1 revocation");
+        assert!(events.windows(2).all(|w| w[0].seq < w[1].seq), "seq must be strictly increasing");
+
+        let revocation = events.last().unwrap();
+        assert_eq!(revocation.neg, 1);
+
+        // Broadcast seqs match the stored frames
+        let mut broadcast_seqs = Vec::new();
+        while let Ok((seq, _)) = rx.try_recv() {
+            broadcast_seqs.push(seq);
+        }
+        let mut frame_seqs: Vec<i64> = events.iter().map(|e| e.frame_seq()).collect();
+        frame_seqs.dedup();
+        assert_eq!(broadcast_seqs, frame_seqs);
+
+        // Stored signature verifies against the rebuilt label
+        let label = label_from_event(revocation.clone())?;
+        let mut unsigned = label.data.clone();
+        unsigned.sig = None;
+        let bytes = serde_ipld_dagcbor::to_vec(&unsigned)?;
+        atrium_crypto::verify::verify_signature(&keypair.did(), &bytes, label.data.sig.as_ref().unwrap())?;
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_fortune_change_is_one_frame() -> Result<()> {
+        use crate::db::get_label_events_after;
+
+        let pool = init_db(":memory:").await?;
+        use rand::rngs::OsRng;
+        let mut rng = OsRng;
+        let keypair = Secp256k1Keypair::create(&mut rng);
+        let labeler_did = "did:plc:labeler";
+        let target_did = "did:plc:target";
+        let (tx, mut rx) = broadcast::channel(100);
+
+        overwrite_fortune(target_did, "daikyo", &pool, &keypair, labeler_did, &tx).await?;
+
+        let (seq, labels) = rx.try_recv()?;
+        assert!(rx.try_recv().is_err(), "A fortune change must be a single frame");
+        assert_eq!(labels.len(), 7);
+        assert_eq!(labels.iter().filter(|l| l.neg.is_none()).count(), 1);
+        assert_eq!(labels.iter().find(|l| l.neg.is_none()).unwrap().val, "daikyo");
+
+        // Every stored row belongs to the broadcast frame
+        let events = get_label_events_after(&pool, 0, 100).await?;
+        assert_eq!(events.len(), 7);
+        assert!(events.iter().all(|e| e.frame_seq() == seq));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_opt_out_stops_labeling() -> Result<()> {
+        let pool = init_db(":memory:").await?;
+        let keypair = Secp256k1Keypair::create(&mut rand::rngs::OsRng);
+        let labeler_did = "did:plc:labeler";
+        let did = "did:plc:target";
+        let (tx, _rx) = broadcast::channel(100);
+
+        assign_fortune(did, None, &pool, &keypair, labeler_did, &tx).await?;
+        // Signed before the opt-out lands, emitted after it
+        let late = sign_new_label(did, "daikichi", false, labeler_did, &keypair)?;
+        opt_out(did, "report", &pool, &keypair, labeler_did, &tx).await?;
+        assert!(get_labels(&pool, did, None, None).await?.is_empty(), "Opting out revokes right away");
+        let events = get_label_events_after(&pool, 0, 1000).await?.len();
+
+        // Nothing labels an opted-out account
+        assert!(assign_fortune(did, None, &pool, &keypair, labeler_did, &tx).await.unwrap_err().is::<OptedOut>());
+        assert!(overwrite_fortune(did, "daikichi", &pool, &keypair, labeler_did, &tx).await.unwrap_err().is::<OptedOut>());
+        assert!(emit_label(&pool, &tx, late).await.unwrap_err().is::<OptedOut>());
+        assert!(get_labels(&pool, did, None, None).await?.is_empty());
+        assert_eq!(get_label_events_after(&pool, 0, 1000).await?.len(), events);
+
+        opt_in(did, &pool, &keypair, labeler_did, &tx).await?;
+        let labels = get_labels(&pool, did, None, None).await?;
+        assert_eq!(labels.iter().filter(|l| l.neg == 0).count(), 1);
+
         Ok(())
     }
 }
@@ -1,11 +1,16 @@
 pub mod api;
+pub mod auth;
+pub mod bsky;
 pub mod config;
 pub mod db;
 pub mod domain;
 pub mod crypto;
+pub mod metrics;
 pub mod poller;
 pub mod scheduler;
 pub mod state;
 
 #[cfg(test)]
 mod tests_serialization;
+#[cfg(test)]
+mod tests_integration;
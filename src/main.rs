@@ -2,6 +2,8 @@ use omikuji::config::config;
 use omikuji::db::init_db;
 use omikuji::api::router;
 use omikuji::state::AppState;
+use omikuji::auth::HttpDidResolver;
+use omikuji::bsky::{AtriumClient, BskyClient};
 use omikuji::crypto::create_keypair;
 use omikuji::{poller, scheduler};
 use std::sync::Arc;
@@ -18,24 +20,33 @@ async fn main() -> anyhow::Result<()> {
     let pool = init_db(&conf.db_path).await?;
 
     let keypair = Arc::new(create_keypair(&conf.signing_key_hex)?);
-    let (tx, _rx) = tokio::sync::broadcast::channel(10000);
+    // Don't hold a receiver here: `receiver_count()` should only count live subscribers.
+    let (tx, _) = tokio::sync::broadcast::channel(10000);
+    let shutdown = Arc::new(tokio::sync::watch::channel(false).0);
+
+    // One session for the labeler account, shared by the poller and the batches
+    let agent: Arc<dyn BskyClient> = Arc::new(AtriumClient::new(&conf.service_url));
+    if let Err(e) = agent.login().await {
+        tracing::error!(error = ?e, "Initial login failed, the poller will retry");
+    }
 
     let pool_clone = pool.clone();
     let keypair_clone = keypair.clone();
     let tx_for_poller = tx.clone();
+    let poller_agent = agent.clone();
     tokio::spawn(async move {
-        if let Err(e) = poller::start_polling(pool_clone, keypair_clone, tx_for_poller).await {
+        if let Err(e) = poller::start_polling(poller_agent, pool_clone, keypair_clone, tx_for_poller).await {
             tracing::error!(error = ?e, "Poller failed");
         }
     });
 
     // Run batch on startup
     let startup_pool = pool.clone();
+    let startup_keypair = keypair.clone();
     let startup_tx = tx.clone();
     tokio::spawn(async move {
-        // HACK: Always run migration on startup for this deployment (random-labeler2 cleanup)
-        tracing::info!("Startup: Force-Running Migration Batch (Source Code Hack)...");
-        if let Err(e) = scheduler::run_migration(startup_pool, startup_tx).await {
+        // One-off cleanup of the random-labeler2 ghosts; a no-op once it has completed
+        if let Err(e) = scheduler::run_migration(startup_pool, startup_keypair, startup_tx).await {
             tracing::error!(error = ?e, "Migration batch failed");
         }
     });
@@ -44,14 +55,18 @@ async fn main() -> anyhow::Result<()> {
 
     let sched_pool = pool.clone();
     let sched_tx = tx.clone();
+    let sched_agent = agent.clone();
+    let sched_keypair = keypair.clone();
     let sched = JobScheduler::new().await?;
 
     sched.add(
         Job::new_async("0 0 15 * * *", move |_uuid, _l| {
             let p = sched_pool.clone();
             let tx = sched_tx.clone();
+            let agent = sched_agent.clone();
+            let keypair = sched_keypair.clone();
             Box::pin(async move {
-                if let Err(e) = scheduler::run_optimized_batch(p, tx).await {
+                if let Err(e) = scheduler::run_optimized_batch(agent, p, keypair, tx).await {
                     tracing::error!(error = ?e, "Scheduler batch failed");
                 }
             })
@@ -62,7 +77,9 @@ async fn main() -> anyhow::Result<()> {
     let state = AppState {
         pool,
         keypair,
-        tx,
+        tx: tx.clone(),
+        shutdown: shutdown.clone(),
+        resolver: Arc::new(HttpDidResolver::new(&conf.plc_url)),
     };
 
     let app = router(state);
@@ -70,7 +87,48 @@ async fn main() -> anyhow::Result<()> {
     let listener = TcpListener::bind(&addr).await?;
     tracing::info!(address = %addr, "Server bound");
 
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            tracing::info!("Shutdown signal received, closing stream subscribers");
+            shutdown.send_replace(true);
+        })
+        .await?;
+
+    // Give subscribers a moment to receive their close frames.
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+    while tx.receiver_count() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    tracing::info!(remaining = tx.receiver_count(), "Server stopped");
 
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = ?e, "Failed to listen for Ctrl+C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => { sig.recv().await; }
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,284 @@
+//! Process-wide counters and gauges, served in the Prometheus text format at `/metrics`.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+/// Upper bounds in seconds of the SQLite query latency buckets.
+const QUERY_LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+#[derive(Default, Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increments now and decrements when the returned guard is dropped.
+    pub fn track(&self) -> GaugeGuard<'_> {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// A counter split by label values, given in the order of the family's label names.
+#[derive(Default, Debug)]
+pub struct CounterVec(Mutex<BTreeMap<Vec<String>, u64>>);
+
+impl CounterVec {
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[&str], n: u64) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        *self.0.lock().unwrap().entry(key).or_default() += n;
+    }
+
+    pub fn get(&self, labels: &[&str]) -> u64 {
+        let key: Vec<String> = labels.iter().map(|l| l.to_string()).collect();
+        self.0.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+}
+
+/// A gauge split by label values, holding the last value set.
+#[derive(Default, Debug)]
+pub struct GaugeVec(Mutex<BTreeMap<Vec<String>, f64>>);
+
+impl GaugeVec {
+    pub fn set(&self, labels: &[&str], value: f64) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        self.0.lock().unwrap().insert(key, value);
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct HistogramData {
+    /// Non-cumulative count per bucket; rendering accumulates them.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug)]
+pub struct HistogramVec {
+    bounds: &'static [f64],
+    series: Mutex<BTreeMap<Vec<String>, HistogramData>>,
+}
+
+impl HistogramVec {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, series: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, labels: &[&str], value: f64) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        let data = series.entry(key).or_insert_with(|| HistogramData {
+            buckets: vec![0; self.bounds.len()],
+            ..Default::default()
+        });
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) {
+            data.buckets[i] += 1;
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+}
+
+/// Observes the time until it is dropped as the latency of one query.
+pub struct QueryTimer {
+    query: &'static str,
+    start: Instant,
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        metrics().db_query_duration.observe(&[self.query], self.start.elapsed().as_secs_f64());
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// Labels written to the event log, by `val` and `neg`.
+    pub labels_emitted: CounterVec,
+    pub negations: Counter,
+    pub stream_subscribers: Gauge,
+    pub frames_sent: Counter,
+    pub frames_failed: Counter,
+    /// Times a subscriber fell behind the broadcast buffer.
+    pub broadcast_lagged: Counter,
+    pub poller_iterations: Counter,
+    /// Failed polls, by `kind` of failure.
+    pub poller_failures: CounterVec,
+    /// Notifications newer than the stored mark, including those caught up on across several pages.
+    pub poller_notifications: Counter,
+    /// Notifications given up on because labeling them failed on our side.
+    pub poller_skipped: Counter,
+    /// Duration of the last run, by `batch`.
+    pub batch_duration: GaugeVec,
+    /// Accounts labeled or revoked, by `batch`.
+    pub batch_users: CounterVec,
+    /// Reports received, by `route` and the kind of action taken.
+    pub report_triggers: CounterVec,
+    /// SQLite query latency, by `query`.
+    pub db_query_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            labels_emitted: CounterVec::default(),
+            negations: Counter::default(),
+            stream_subscribers: Gauge::default(),
+            frames_sent: Counter::default(),
+            frames_failed: Counter::default(),
+            broadcast_lagged: Counter::default(),
+            poller_iterations: Counter::default(),
+            poller_failures: CounterVec::default(),
+            poller_notifications: Counter::default(),
+            poller_skipped: Counter::default(),
+            batch_duration: GaugeVec::default(),
+            batch_users: CounterVec::default(),
+            report_triggers: CounterVec::default(),
+            db_query_duration: HistogramVec::new(QUERY_LATENCY_BUCKETS),
+        }
+    }
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    pub fn time_query(&self, query: &'static str) -> QueryTimer {
+        QueryTimer { query, start: Instant::now() }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_vec(&mut out, "omikuji_labels_emitted_total", "counter", "Labels emitted, by value and polarity.", &["val", "neg"], &self.labels_emitted.0);
+        render_one(&mut out, "omikuji_label_negations_total", "counter", "Negation labels emitted.", self.negations.get());
+        render_one(&mut out, "omikuji_stream_subscribers", "gauge", "Connected subscribeLabels clients.", self.stream_subscribers.get());
+        render_one(&mut out, "omikuji_stream_frames_sent_total", "counter", "Frames sent to subscribers, live and replayed.", self.frames_sent.get());
+        render_one(&mut out, "omikuji_stream_frames_failed_total", "counter", "Frames that could not be encoded or sent.", self.frames_failed.get());
+        render_one(&mut out, "omikuji_broadcast_lagged_total", "counter", "Times a subscriber fell behind the broadcast buffer.", self.broadcast_lagged.get());
+        render_one(&mut out, "omikuji_poller_iterations_total", "counter", "Notification polls attempted.", self.poller_iterations.get());
+        render_vec(&mut out, "omikuji_poller_failures_total", "counter", "Notification polls that failed, by kind of failure.", &["kind"], &self.poller_failures.0);
+        render_one(&mut out, "omikuji_poller_notifications_total", "counter", "New notifications read by the poller.", self.poller_notifications.get());
+        render_one(&mut out, "omikuji_poller_skipped_total", "counter", "Notifications skipped because labeling them failed.", self.poller_skipped.get());
+        render_vec(&mut out, "omikuji_batch_last_duration_seconds", "gauge", "Duration of the last batch run.", &["batch"], &self.batch_duration.0);
+        render_vec(&mut out, "omikuji_batch_users_processed_total", "counter", "Accounts labeled or revoked by batches.", &["batch"], &self.batch_users.0);
+        render_vec(&mut out, "omikuji_report_triggers_total", "counter", "Reports received, by route and action taken.", &["route", "action"], &self.report_triggers.0);
+        render_histogram(&mut out, "omikuji_db_query_duration_seconds", "SQLite query latency.", &["query"], &self.db_query_duration);
+        out
+    }
+}
+
+fn render_one(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+}
+
+fn render_vec<V: std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str, names: &[&str], series: &Mutex<BTreeMap<Vec<String>, V>>) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    for (values, value) in series.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}}} {}", name, label_pairs(names, values), value);
+    }
+}
+
+fn render_histogram(out: &mut String, name: &str, help: &str, names: &[&str], histogram: &HistogramVec) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    for (values, data) in histogram.series.lock().unwrap().iter() {
+        let labels = label_pairs(names, values);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.bounds.iter().zip(&data.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, data.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, data.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, data.count);
+    }
+}
+
+fn label_pairs(names: &[&str], values: &[String]) -> String {
+    names.iter().zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let m = Metrics::default();
+        m.labels_emitted.inc(&["kichi", "false"]);
+        m.labels_emitted.add(&["kyo", "true"], 2);
+        m.negations.add(2);
+        let subscriber = m.stream_subscribers.track();
+        m.report_triggers.inc(&["command", "say \"hi\""]);
+        m.db_query_duration.observe(&["get_labels"], 0.002);
+        m.db_query_duration.observe(&["get_labels"], 2.0);
+
+        let text = m.render();
+        assert!(text.contains("# TYPE omikuji_labels_emitted_total counter\n"));
+        assert!(text.contains("omikuji_labels_emitted_total{val=\"kichi\",neg=\"false\"} 1\n"));
+        assert!(text.contains("omikuji_labels_emitted_total{val=\"kyo\",neg=\"true\"} 2\n"));
+        assert!(text.contains("omikuji_label_negations_total 2\n"));
+        assert!(text.contains("omikuji_stream_subscribers 1\n"));
+        assert!(text.contains("omikuji_report_triggers_total{route=\"command\",action=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains("omikuji_db_query_duration_seconds_bucket{query=\"get_labels\",le=\"0.001\"} 0\n"));
+        assert!(text.contains("omikuji_db_query_duration_seconds_bucket{query=\"get_labels\",le=\"0.0025\"} 1\n"));
+        assert!(text.contains("omikuji_db_query_duration_seconds_bucket{query=\"get_labels\",le=\"1\"} 1\n"));
+        assert!(text.contains("omikuji_db_query_duration_seconds_bucket{query=\"get_labels\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("omikuji_db_query_duration_seconds_count{query=\"get_labels\"} 2\n"));
+
+        drop(subscriber);
+        assert_eq!(m.stream_subscribers.get(), 0);
+    }
+}
@@ -1,102 +1,452 @@
 use anyhow::Result;
-use atrium_api::agent::atp_agent::AtpAgent;
-use atrium_xrpc_client::reqwest::ReqwestClient;
 use std::time::Duration;
 use tokio::time::sleep;
+use crate::bsky::{BskyClient, Notification};
 use crate::config::config;
-use crate::db::DbPool;
+use crate::db::{get_poller_state, set_poller_state, DbPool};
 use atrium_crypto::keypair::Secp256k1Keypair;
 use tokio::sync::broadcast;
 use atrium_api::com::atproto::label::defs::Label;
-use crate::domain::labeling::assign_fortune;
+use crate::domain::labeling::{assign_fortune, OptedOut};
+use crate::metrics::metrics;
 use std::sync::Arc;
-use atrium_api::agent::atp_agent::store::MemorySessionStore;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use atrium_api::xrpc;
+
+/// `poller_state` key of the newest notification `indexedAt` already processed.
+const LAST_SEEN_KEY: &str = "last_seen_at";
+/// Notifications requested per page.
+const PAGE_LIMIT: u8 = 50;
+/// Pages read at most in one regular poll, enough for a burst of several hundred notifications.
+const POLL_MAX_PAGES: usize = 10;
+/// Pages read at most while catching up after a restart.
+const CATCH_UP_MAX_PAGES: usize = 20;
+
+/// Delay between polls while everything works.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest delay between retries after repeated failures.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// The poller counts as stuck when it hasn't succeeded for this long.
+const STALE_AFTER_SECS: i64 = 900;
+
+/// Whether the poller is running and when it last completed a pass, for health checks.
+#[derive(Debug, Default)]
+pub struct Liveness {
+    running: AtomicBool,
+    /// Unix seconds of the last successful pass, 0 if there was none.
+    last_success: AtomicI64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessStatus {
+    pub running: bool,
+    pub last_success: Option<i64>,
+    /// Running, but without a successful pass for too long.
+    pub stale: bool,
+}
+
+impl Liveness {
+    fn start(&self, now: i64) {
+        self.running.store(true, Ordering::Relaxed);
+        // Give the first pass the full grace period
+        self.last_success.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed).ok();
+    }
+
+    fn record_success(&self, now: i64) {
+        self.last_success.store(now, Ordering::Relaxed);
+    }
+
+    pub fn status(&self, now: i64) -> LivenessStatus {
+        let running = self.running.load(Ordering::Relaxed);
+        let last_success = Some(self.last_success.load(Ordering::Relaxed)).filter(|&t| t > 0);
+        let stale = running && last_success.is_none_or(|t| now - t > STALE_AFTER_SECS);
+        LivenessStatus { running, last_success, stale }
+    }
+}
+
+static LIVENESS: Liveness = Liveness {
+    running: AtomicBool::new(false),
+    last_success: AtomicI64::new(0),
+};
+
+pub fn liveness() -> &'static Liveness {
+    &LIVENESS
+}
+
+/// How a failed poll is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureKind {
+    /// The session was rejected; log in again before retrying.
+    Auth,
+    /// Network trouble, rate limits or server errors on the Bluesky side.
+    Transient,
+    /// Anything else on our side that would fail the same way again, such as a constraint violation.
+    Internal,
+}
+
+impl FailureKind {
+    fn name(&self) -> &'static str {
+        match self {
+            FailureKind::Auth => "auth",
+            FailureKind::Transient => "transient",
+            FailureKind::Internal => "internal",
+        }
+    }
+}
+
+/// Error names a PDS uses for missing, invalid or expired sessions.
+const AUTH_ERRORS: &[&str] = &["AuthenticationRequired", "AuthMissing", "ExpiredToken", "InvalidToken", "AccountTakedown"];
+
+fn classify(error: &anyhow::Error) -> FailureKind {
+    use atrium_api::app::bsky::notification::{list_notifications, update_seen};
+    use atrium_api::com::atproto::server::create_session;
+
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<xrpc::Error<list_notifications::Error>>() {
+            return classify_xrpc(e);
+        }
+        if let Some(e) = cause.downcast_ref::<xrpc::Error<update_seen::Error>>() {
+            return classify_xrpc(e);
+        }
+        if let Some(e) = cause.downcast_ref::<xrpc::Error<create_session::Error>>() {
+            return classify_xrpc(e);
+        }
+        if let Some(e) = cause.downcast_ref::<sqlx::Error>() {
+            return classify_sqlx(e);
+        }
+    }
+    FailureKind::Internal
+}
+
+/// SQLite primary result codes of a database that is momentarily in use elsewhere.
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+fn classify_sqlx(error: &sqlx::Error) -> FailureKind {
+    match error {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => FailureKind::Transient,
+        sqlx::Error::Database(e) => {
+            // Extended result codes keep the primary code in the low byte
+            let code = e.code().and_then(|c| c.parse::<i32>().ok()).unwrap_or(0) & 0xff;
+            if code == SQLITE_BUSY || code == SQLITE_LOCKED {
+                FailureKind::Transient
+            } else {
+                FailureKind::Internal
+            }
+        }
+        _ => FailureKind::Internal,
+    }
+}
+
+fn classify_xrpc<E: std::fmt::Debug>(error: &xrpc::Error<E>) -> FailureKind {
+    match error {
+        xrpc::Error::Authentication(_) => FailureKind::Auth,
+        xrpc::Error::XrpcResponse(e) => {
+            let name = match &e.error {
+                Some(xrpc::error::XrpcErrorKind::Undefined(body)) => body.error.as_deref(),
+                _ => None,
+            };
+            if e.status == xrpc::http::StatusCode::UNAUTHORIZED || name.is_some_and(|n| AUTH_ERRORS.contains(&n)) {
+                FailureKind::Auth
+            } else {
+                FailureKind::Transient
+            }
+        }
+        xrpc::Error::HttpClient(_) | xrpc::Error::HttpRequest(_) => FailureKind::Transient,
+        _ => FailureKind::Internal,
+    }
+}
+
+/// Exponential backoff from the poll interval, capped at `MAX_BACKOFF`.
+/// `jitter` in `[0, 1)` picks a point in the upper half of the window, so retries from restarts don't line up.
+fn backoff_delay(failures: u32, jitter: f64) -> Duration {
+    let window = POLL_INTERVAL.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1))).min(MAX_BACKOFF);
+    window.mul_f64(0.5 + jitter / 2.0)
+}
 
 pub async fn start_polling(
+    client: Arc<dyn BskyClient>,
     pool: DbPool,
     keypair: Arc<Secp256k1Keypair>,
     tx: broadcast::Sender<(i64, Vec<Label>)>
 ) -> Result<()> {
-    let conf = config();
-    let agent = AtpAgent::new(ReqwestClient::new("https://bsky.social"), MemorySessionStore::default());
-
-    if let Some(pwd) = &conf.labeler_password {
-        let identifier = conf.handle.as_deref().unwrap_or(&conf.labeler_did);
-        tracing::info!(identifier, "Attempting login");
-        agent.login(identifier, pwd).await?;
-        tracing::info!("Bot logged in");
-    } else {
+    if config().labeler_password.is_none() {
         tracing::warn!("No password provided, skipping bot login (polling will fail).");
         return Ok(());
     }
+    liveness().start(chrono::Utc::now().timestamp());
 
-    let mut last_seen_at: Option<String> = None;
-
+    let mut poller = Poller::new(client, pool, keypair, tx).await?;
     loop {
-        match check_notifications(&agent, &pool, &keypair, &last_seen_at, &tx).await {
-            Ok(new_last_seen) => {
-                if let Some(t) = new_last_seen {
-                    last_seen_at = Some(t);
+        let delay = poller.step().await;
+        sleep(delay).await;
+    }
+}
+
+/// The notification loop, one pass at a time.
+pub struct Poller {
+    client: Arc<dyn BskyClient>,
+    pool: DbPool,
+    keypair: Arc<Secp256k1Keypair>,
+    tx: broadcast::Sender<(i64, Vec<Label>)>,
+    /// Newest notification already processed, kept across restarts.
+    last_seen_at: Option<String>,
+    /// Until a pass succeeds, allow paging much further back to reach the stored mark.
+    catching_up: bool,
+    /// A pass that ran out of pages before reaching the mark, continued by the next step.
+    backlog: Option<Backlog>,
+    logged_in: bool,
+    /// Failures since the last successful pass.
+    failures: u32,
+}
+
+impl Poller {
+    pub async fn new(
+        client: Arc<dyn BskyClient>,
+        pool: DbPool,
+        keypair: Arc<Secp256k1Keypair>,
+        tx: broadcast::Sender<(i64, Vec<Label>)>,
+    ) -> Result<Self> {
+        let last_seen_at = get_poller_state(&pool, LAST_SEEN_KEY).await?;
+        match &last_seen_at {
+            Some(mark) => tracing::info!(mark, "Catching up on notifications since the stored mark"),
+            None => tracing::info!("No stored notification mark, starting from the newest notifications"),
+        }
+        Ok(Self {
+            logged_in: client.has_session().await,
+            catching_up: last_seen_at.is_some(),
+            client,
+            pool,
+            keypair,
+            tx,
+            last_seen_at,
+            backlog: None,
+            failures: 0,
+        })
+    }
+
+    /// Logs in if needed, or reads and processes new notifications. Returns how long to wait before the next step.
+    pub async fn step(&mut self) -> Duration {
+        metrics().poller_iterations.inc();
+        let result = if self.logged_in {
+            let max_pages = if self.catching_up { CATCH_UP_MAX_PAGES } else { POLL_MAX_PAGES };
+            // A failed pass starts over from the newest page; the mark hasn't moved past anything unread
+            let backlog = self.backlog.take();
+            check_notifications(self.client.as_ref(), &self.pool, &self.keypair, &self.last_seen_at, backlog, max_pages, &self.tx).await
+        } else {
+            match self.client.login().await {
+                Ok(_) => {
+                    self.logged_in = true;
+                    return Duration::ZERO;
                 }
+                Err(e) => Err(e),
             }
-            Err(_e) => {
+        };
+
+        match result {
+            Ok(Poll { backlog: Some(backlog), pages, .. }) => {
+                tracing::info!(pages, read = backlog.notifications.len(), mark = ?self.last_seen_at, "Still paging back to the stored mark");
+                self.backlog = Some(backlog);
+                self.failures = 0;
+                liveness().record_success(chrono::Utc::now().timestamp());
+                Duration::ZERO
+            }
+            Ok(poll) => {
+                if self.catching_up {
+                    tracing::info!(processed = poll.processed, pages = poll.pages, mark = ?poll.last_seen_at, "Catch-up complete, polling every 10 seconds");
+                    self.catching_up = false;
+                } else if poll.pages > 1 {
+                    tracing::info!(processed = poll.processed, pages = poll.pages, "Caught up on a burst of notifications");
+                }
+                if poll.last_seen_at.is_some() {
+                    self.last_seen_at = poll.last_seen_at;
+                }
+                if self.failures > 0 {
+                    tracing::info!(failures = self.failures, "Poller recovered");
+                    self.failures = 0;
+                }
+                liveness().record_success(chrono::Utc::now().timestamp());
+                POLL_INTERVAL
+            }
+            Err(e) => {
+                self.failures += 1;
+                let failures = self.failures;
+                let kind = classify(&e);
+                metrics().poller_failures.inc(&[kind.name()]);
+                let delay = backoff_delay(failures, rand::random());
+                match kind {
+                    FailureKind::Auth => {
+                        tracing::warn!(error = ?e, failures, ?delay, "Session rejected, logging in again");
+                        self.logged_in = false;
+                    }
+                    FailureKind::Transient => tracing::warn!(error = ?e, failures, ?delay, "Poll failed, backing off"),
+                    FailureKind::Internal => tracing::error!(error = ?e, failures, ?delay, "Poll failed, backing off"),
+                }
+                // Whatever was processed before the failure has been stored
+                if let Ok(Some(mark)) = get_poller_state(&self.pool, LAST_SEEN_KEY).await {
+                    self.last_seen_at = Some(mark);
+                }
+                delay
             }
         }
-        sleep(Duration::from_secs(10)).await;
     }
+
+    pub fn last_seen_at(&self) -> Option<&str> {
+        self.last_seen_at.as_deref()
+    }
+}
+
+/// Result of one pass over the notifications.
+#[derive(Debug)]
+struct Poll {
+    /// The new high-water mark.
+    last_seen_at: Option<String>,
+    /// Notifications newer than the previous mark.
+    processed: usize,
+    pages: usize,
+    /// Set when the pass stopped at `max_pages` before reaching the mark; nothing was processed yet.
+    backlog: Option<Backlog>,
+}
+
+/// New notifications read so far by a pass that hasn't reached the stored mark.
+#[derive(Debug)]
+struct Backlog {
+    notifications: Vec<Notification>,
+    /// Where the next page starts.
+    cursor: String,
 }
 
+/// Pages back through notifications until the first one already processed, then labels new
+/// followers and likers oldest-first, storing the mark as it goes. After `max_pages` pages the
+/// pass stops and returns what it read as a backlog, to be passed back in to continue paging.
 async fn check_notifications(
-    agent: &AtpAgent<MemorySessionStore, ReqwestClient>,
+    client: &dyn BskyClient,
     pool: &DbPool,
     keypair: &Secp256k1Keypair,
     last_seen_at: &Option<String>,
+    backlog: Option<Backlog>,
+    max_pages: usize,
     tx: &broadcast::Sender<(i64, Vec<Label>)>
-) -> Result<Option<String>> {
-    let limit: i32 = 50;
-    let resp = agent.api.app.bsky.notification.list_notifications(
-         atrium_api::app::bsky::notification::list_notifications::ParametersData {
-             cursor: None,
-             limit: Some((limit as u8).try_into().unwrap()),
-             priority: None,
-             reasons: None,
-             seen_at: None,
-         }.into()
-    ).await?;
+) -> Result<Poll> {
+    let is_new = |indexed_at: &str| last_seen_at.as_deref().is_none_or(|last| indexed_at > last);
 
-    let mut max_indexed_at = last_seen_at.clone();
+    let (mut notifications, mut cursor) = match backlog {
+        Some(b) => (b.notifications, Some(b.cursor)),
+        None => (Vec::new(), None),
+    };
+    let mut pages = 0;
+    loop {
+        let page = client.list_notifications(cursor, PAGE_LIMIT).await?;
+        pages += 1;
 
-    for notif in &resp.notifications {
-        let indexed_at = notif.indexed_at.as_str().to_string();
-        if max_indexed_at.is_none() || indexed_at > max_indexed_at.as_ref().unwrap().clone() {
-            max_indexed_at = Some(indexed_at.clone());
+        // Without a mark there is nothing to page back to, so only the newest page is read
+        let reached_mark = last_seen_at.is_none() || page.items.iter().any(|n| !is_new(&n.indexed_at));
+        notifications.extend(page.items.into_iter().filter(|n| is_new(&n.indexed_at)));
+        cursor = page.cursor;
+        if reached_mark || cursor.is_none() {
+            break;
         }
-
-        if let Some(last) = last_seen_at {
-            if &indexed_at <= last {
-                continue;
-            }
+        if pages == max_pages
+            && let Some(cursor) = cursor
+        {
+            // Processing these now would move the mark past the older ones still unread
+            let backlog = Backlog { notifications, cursor };
+            return Ok(Poll { last_seen_at: last_seen_at.clone(), processed: 0, pages, backlog: Some(backlog) });
         }
+    }
+
+    // Pages come newest first
+    notifications.sort_by(|a, b| a.indexed_at.cmp(&b.indexed_at));
+    let processed = notifications.len();
+    metrics().poller_notifications.add(processed as u64);
 
+    let mut max_indexed_at = last_seen_at.clone();
+    for notif in &notifications {
         match notif.reason.as_str() {
             "follow" | "like" => {
-                let did = &notif.author.did;
-                let handle = notif.author.handle.as_str();
-
-                assign_fortune(did.as_str(), Some(handle), pool, keypair, &config().labeler_did, tx).await?;
+                if let Err(e) = assign_fortune(&notif.author_did, Some(&notif.author_handle), pool, keypair, &config().labeler_did, tx).await {
+                    if e.is::<OptedOut>() {
+                        tracing::info!(did = notif.author_did, "Skipping notification, user has opted out");
+                        max_indexed_at = Some(notif.indexed_at.clone());
+                        continue;
+                    }
+                    // Retrying won't fix this one, and would hold up every notification after it
+                    if classify(&e) == FailureKind::Internal {
+                        tracing::error!(did = notif.author_did, reason = notif.reason, indexed_at = notif.indexed_at, error = ?e, "Failed to label, skipping notification");
+                        metrics().poller_skipped.inc();
+                        max_indexed_at = Some(notif.indexed_at.clone());
+                        continue;
+                    }
+                    // Resume from this notification next time
+                    if let Some(t) = &max_indexed_at
+                        && max_indexed_at != *last_seen_at
+                    {
+                        set_poller_state(&mut *pool.acquire().await?, LAST_SEEN_KEY, t).await?;
+                    }
+                    return Err(e);
+                }
             }
              _ => {}
         }
+        max_indexed_at = Some(notif.indexed_at.clone());
+    }
+
+    if let Some(t) = &max_indexed_at
+        && max_indexed_at != *last_seen_at
+    {
+         set_poller_state(&mut *pool.acquire().await?, LAST_SEEN_KEY, t).await?;
+         client.update_seen(t).await?;
     }
 
-    if let Some(t) = &max_indexed_at {
-         let dt = chrono::DateTime::parse_from_rfc3339(t)?;
-         agent.api.app.bsky.notification.update_seen(
-             atrium_api::app::bsky::notification::update_seen::InputData {
-                 seen_at: atrium_api::types::string::Datetime::new(dt),
-             }.into()
-         ).await?;
+    Ok(Poll { last_seen_at: max_indexed_at, processed, pages, backlog: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(1, 0.0), Duration::from_secs(5));
+        assert_eq!(backoff_delay(1, 0.999), Duration::from_secs(10).mul_f64(0.9995));
+        assert_eq!(backoff_delay(3, 0.0), Duration::from_secs(20));
+        // Capped, and no overflow after a very long outage
+        assert_eq!(backoff_delay(10, 0.0), MAX_BACKOFF / 2);
+        assert_eq!(backoff_delay(u32::MAX, 0.0), MAX_BACKOFF / 2);
     }
 
-    Ok(max_indexed_at)
+    #[test]
+    fn test_classify() {
+        let response = |status: u16, name: Option<&str>| -> anyhow::Error {
+            xrpc::Error::<atrium_api::app::bsky::notification::list_notifications::Error>::XrpcResponse(xrpc::error::XrpcError {
+                status: xrpc::http::StatusCode::from_u16(status).unwrap(),
+                error: name.map(|n| xrpc::error::XrpcErrorKind::Undefined(xrpc::error::ErrorResponseBody {
+                    error: Some(n.to_string()),
+                    message: None,
+                })),
+            }).into()
+        };
+        assert_eq!(classify(&response(400, Some("ExpiredToken"))), FailureKind::Auth);
+        assert_eq!(classify(&response(401, None)), FailureKind::Auth);
+        assert_eq!(classify(&response(502, None)), FailureKind::Transient);
+        assert_eq!(classify(&response(429, Some("RateLimitExceeded"))), FailureKind::Transient);
+        assert_eq!(classify(&response(502, None).context("while polling")), FailureKind::Transient);
+        assert_eq!(classify(&anyhow::anyhow!("Invalid DID")), FailureKind::Internal);
+        assert_eq!(classify(&sqlx::Error::PoolTimedOut.into()), FailureKind::Transient);
+        assert_eq!(classify(&anyhow::Error::from(sqlx::Error::Io(std::io::ErrorKind::BrokenPipe.into())).context("while labeling")), FailureKind::Transient);
+        assert_eq!(classify(&sqlx::Error::RowNotFound.into()), FailureKind::Internal);
+    }
+
+    #[test]
+    fn test_liveness() {
+        let liveness = Liveness::default();
+        assert_eq!(liveness.status(1000), LivenessStatus { running: false, last_success: None, stale: false });
+
+        liveness.start(1000);
+        assert!(!liveness.status(1000 + STALE_AFTER_SECS).stale);
+        assert!(liveness.status(1001 + STALE_AFTER_SECS).stale);
+
+        liveness.record_success(2000);
+        assert_eq!(liveness.status(2010), LivenessStatus { running: true, last_success: Some(2000), stale: false });
+    }
 }
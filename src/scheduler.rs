@@ -1,102 +1,128 @@
 use anyhow::Result;
-use atrium_api::agent::atp_agent::AtpAgent;
-use atrium_xrpc_client::reqwest::ReqwestClient;
+use crate::bsky::{ensure_logged_in, BskyClient};
 use crate::config::config;
-use crate::db::DbPool;
-use crate::domain::labeling::{assign_fortune, revoke_fortune, overwrite_fortune};
+use crate::db::{get_poller_state, list_opt_outs, set_poller_state, DbPool};
+use crate::domain::labeling::{assign_fortune, revoke_fortune, overwrite_fortune, sign_new_label, emit_labels};
 use crate::domain::fortune::Fortune;
 use std::str::FromStr;
-use crate::crypto::create_keypair;
+use atrium_crypto::keypair::Secp256k1Keypair;
+use crate::metrics::metrics;
 
 use sqlx::Row;
 
-use atrium_api::agent::atp_agent::store::MemorySessionStore;
-
 use tokio::sync::broadcast;
 use atrium_api::com::atproto::label::defs::Label;
 
 use std::sync::Arc;
+use std::time::Duration;
 use tracing;
 
-pub async fn run_optimized_batch(pool: DbPool, tx: broadcast::Sender<(i64, Vec<Label>)>) -> Result<()> {
+/// `poller_state` key recording when the ID rotation migration finished, so it only ever runs once.
+const MIGRATION_DONE_KEY: &str = "id_rotation_migrated_at";
+
+/// Followers requested per page.
+const FOLLOWERS_PAGE_LIMIT: u8 = 100;
+/// Pause between follower pages and between labeled accounts, to go easy on the PDS and subscribers.
+const PAGE_DELAY: Duration = Duration::from_millis(100);
+const USER_DELAY: Duration = Duration::from_millis(50);
+
+/// Labels every follower with today's fortune and revokes accounts that stopped following.
+pub async fn run_optimized_batch(
+    client: Arc<dyn BskyClient>,
+    pool: DbPool,
+    keypair: Arc<Secp256k1Keypair>,
+    tx: broadcast::Sender<(i64, Vec<Label>)>,
+) -> Result<()> {
     tracing::info!("Running optimized batch");
+    let started = std::time::Instant::now();
     let conf = config();
-    let agent = AtpAgent::new(ReqwestClient::new("https://bsky.social"), MemorySessionStore::default());
 
-    if let Some(pwd) = &conf.labeler_password {
-        agent.login(conf.handle.as_deref().unwrap_or(&conf.labeler_did), pwd).await?;
-    } else {
+    if !ensure_logged_in(client.as_ref()).await? {
         tracing::info!("Skipping batch due to missing password.");
         return Ok(());
     }
 
-    let keypair = Arc::new(create_keypair(&conf.signing_key_hex)?);
-
     let rows = sqlx::query("SELECT DISTINCT uri FROM labels WHERE is_deleted = 0").fetch_all(&pool).await?;
     let local_dids: Vec<String> = rows.iter().map(|r| r.get("uri")).collect();
     tracing::info!(count = local_dids.len(), "Found local users");
 
-    let mut followers_map = std::collections::HashMap::new();
-    let mut cursor: Option<String> = None;
     let actor = conf.handle.as_deref().unwrap_or(&conf.labeler_did);
+    if !actor.starts_with("did:") {
+        // Labeling the followers of the wrong account would be hard to notice
+        match client.resolve_handle(actor).await {
+            Ok(did) if did != conf.labeler_did => tracing::warn!(handle = actor, did, labeler_did = conf.labeler_did, "HANDLE does not belong to LABELER_DID"),
+            Ok(_) => {}
+            Err(e) => tracing::warn!(handle = actor, error = ?e, "Failed to resolve HANDLE"),
+        }
+    }
 
+    let mut followers_map = std::collections::HashMap::new();
+    let mut cursor: Option<String> = None;
     loop {
-        let resp = agent.api.app.bsky.graph.get_followers(
-            atrium_api::app::bsky::graph::get_followers::ParametersData {
-                actor: if actor.starts_with("did:") {
-                    atrium_api::types::string::AtIdentifier::Did(atrium_api::types::string::Did::new(actor.to_string()).expect("Invalid DID"))
-                } else {
-                    atrium_api::types::string::AtIdentifier::Handle(atrium_api::types::string::Handle::new(actor.to_string()).expect("Invalid Handle"))
-                }.into(),
-                cursor: cursor.clone(),
-                limit: Some((100 as u8).try_into().unwrap()),
-            }.into()
-        ).await?;
-
-        for f in &resp.followers {
-            followers_map.insert(f.did.as_str().to_string(), f.handle.as_str().to_string());
+        let page = client.list_followers(actor, cursor, FOLLOWERS_PAGE_LIMIT).await?;
+        for f in page.items {
+            followers_map.insert(f.did, f.handle);
         }
 
-        if resp.cursor.is_none() {
+        if page.cursor.is_none() {
             break;
         }
-        cursor = resp.cursor.clone();
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        cursor = page.cursor;
+        tokio::time::sleep(PAGE_DELAY).await;
     }
     tracing::info!(count = followers_map.len(), "Fetched followers");
 
+    let opted_out: std::collections::HashSet<String> = list_opt_outs(&pool).await?.into_iter().map(|o| o.did).collect();
+    followers_map.retain(|did, _| !opted_out.contains(did));
+    tracing::info!(count = followers_map.len(), opted_out = opted_out.len(), "Labeling followers who haven't opted out");
+
     for (did, handle) in &followers_map {
-        if let Err(e) = assign_fortune(did, Some(handle), &pool, &keypair, &conf.labeler_did, &tx).await {
-            tracing::error!(did, error = ?e, "Error assigning fortune");
+        match assign_fortune(did, Some(handle), &pool, &keypair, &conf.labeler_did, &tx).await {
+            Ok(()) => metrics().batch_users.inc(&["daily"]),
+            Err(e) => tracing::error!(did, error = ?e, "Error assigning fortune"),
         }
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        tokio::time::sleep(USER_DELAY).await;
     }
 
     for did in local_dids {
-        if !followers_map.contains_key(&did) {
-            if let Err(e) = revoke_fortune(&did, &pool, &keypair, &config().labeler_did, &tx).await {
-                tracing::error!(did, error = ?e, "Error revoking fortune");
+        // Opted-out accounts were revoked when they opted out
+        if !followers_map.contains_key(&did) && !opted_out.contains(&did) {
+            match revoke_fortune(&did, &pool, &keypair, &config().labeler_did, &tx).await {
+                Ok(()) => metrics().batch_users.inc(&["daily"]),
+                Err(e) => tracing::error!(did, error = ?e, "Error revoking fortune"),
             }
-            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            tokio::time::sleep(USER_DELAY).await;
         }
     }
 
-    tracing::info!("Batch complete");
+    metrics().batch_duration.set(&["daily"], started.elapsed().as_secs_f64());
+    tracing::info!(elapsed = ?started.elapsed(), "Batch complete");
     Ok(())
 }
 
-pub async fn run_migration(pool: DbPool, tx: broadcast::Sender<(i64, Vec<Label>)>) -> Result<()> {
+pub async fn run_migration(
+    pool: DbPool,
+    keypair: Arc<Secp256k1Keypair>,
+    tx: broadcast::Sender<(i64, Vec<Label>)>,
+) -> Result<()> {
+    if let Some(done_at) = get_poller_state(&pool, MIGRATION_DONE_KEY).await? {
+        tracing::info!(done_at, "Migration batch (ID Rotation) already ran, skipping");
+        return Ok(());
+    }
     tracing::info!("Starting migration batch (ID Rotation)");
+    let started = std::time::Instant::now();
     let conf = config();
 
-    let keypair = Arc::new(create_keypair(&conf.signing_key_hex)?);
-
     // 1. Get ALL users ever seen (even if soft deleted, we need to revoke their old ghosts)
     let rows = sqlx::query("SELECT DISTINCT uri FROM labels").fetch_all(&pool).await?;
-    let all_dids: Vec<String> = rows.iter().map(|r| r.get("uri")).collect();
+    let mut all_dids: Vec<String> = rows.iter().map(|r| r.get("uri")).collect();
     tracing::info!(count = all_dids.len(), "Found ALL users for migration (active + inactive)");
 
-    let _agent = AtpAgent::new(ReqwestClient::new("https://bsky.social"), MemorySessionStore::default());
+    // Opted-out accounts had their labels revoked when they opted out and are left alone
+    let opted_out: std::collections::HashSet<String> = list_opt_outs(&pool).await?.into_iter().map(|o| o.did).collect();
+    all_dids.retain(|did| !opted_out.contains(did));
+    tracing::info!(count = all_dids.len(), "Migrating users who haven't opted out");
+
     // Wait for at least one listener (AppView) to connect, otherwise events are lost in void.
     tracing::info!("Waiting for active listeners (AppView)...");
     let mut waits = 0;
@@ -109,10 +135,6 @@ pub async fn run_migration(pool: DbPool, tx: broadcast::Sender<(i64, Vec<Label>)
         waits += 1;
     }
     tracing::info!(listeners = tx.receiver_count(), "Listeners active. Starting migration.");
-    if let Some(_pwd) = &conf.labeler_password {
-         // Login optional for migration but good for consistent object creation if needed?
-         // assign_fortune doesn't use agent.
-    }
 
     for did in all_dids {
         // Check if user is currently active (to decide whether to re-apply)
@@ -143,41 +165,23 @@ pub async fn run_migration(pool: DbPool, tx: broadcast::Sender<(i64, Vec<Label>)
         ];
 
         let mut force_negation_labels = Vec::new();
-        let now_str = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
-        let cts = atrium_api::types::string::Datetime::from_str(&now_str).expect("Invalid timestamp");
-
         for val in old_label_strings {
-            let mut label_data = atrium_api::com::atproto::label::defs::LabelData {
-                cid: None,
-                cts: cts.clone(),
-                exp: None,
-                neg: Some(true),
-                sig: None,
-                src: atrium_api::types::string::Did::new(conf.labeler_did.to_string()).expect("Invalid DID"),
-                uri: did.clone(),
-                val: val.to_string(),
-                ver: Some(1),
-            };
-
-            if let Ok(_) = crate::crypto::sign_label(&mut label_data, &keypair) {
-                force_negation_labels.push(Label {
-                    data: label_data,
-                    extra_data: ipld_core::ipld::Ipld::Null,
-                });
+            match sign_new_label(&did, val, true, &conf.labeler_did, &keypair) {
+                Ok(label) => force_negation_labels.push(label),
+                Err(e) => tracing::error!(did, val, error = ?e, "Failed to sign force negation"),
             }
         }
 
         if !force_negation_labels.is_empty() {
-             // 0 sequence for revocation
-             if let Err(_e) = tx.send((0, force_negation_labels)) {
-                 tracing::error!(did, "Failed to broadcast force negations");
-             } else {
-                 tracing::info!(did, "Broadcasted FORCE negation for old labels");
-             }
+            if let Err(e) = emit_labels(&pool, &tx, force_negation_labels).await {
+                tracing::error!(did, error = ?e, "Failed to emit force negations");
+            } else {
+                tracing::info!(did, "Emitted FORCE negation for old labels");
+            }
         }
 
         // Ensure DB is consistent (Soft Delete)
-        if let Err(e) = crate::db::delete_label(&pool, &did).await {
+        if let Err(e) = crate::db::delete_label(&mut *pool.acquire().await?, &did).await {
              tracing::error!(did, error = ?e, "Error soft deleting label");
         }
 
@@ -199,10 +203,17 @@ pub async fn run_migration(pool: DbPool, tx: broadcast::Sender<(i64, Vec<Label>)
             tracing::info!(did, "User is inactive, skipped re-application (Ghost cleanup only)");
         }
 
+        metrics().batch_users.inc(&["migration"]);
+
         // Throttle to avoid flooding broadcast channel too fast?
         tokio::time::sleep(std::time::Duration::from_millis(20)).await;
     }
 
-    tracing::info!("Migration complete");
+    // Every negation and relabel above is in label_events now, so running again would only append duplicates
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    set_poller_state(&mut *pool.acquire().await?, MIGRATION_DONE_KEY, &now).await?;
+
+    metrics().batch_duration.set(&["migration"], started.elapsed().as_secs_f64());
+    tracing::info!(elapsed = ?started.elapsed(), "Migration complete");
     Ok(())
 }
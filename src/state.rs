@@ -2,10 +2,15 @@ use crate::db::DbPool;
 use atrium_api::com::atproto::label::defs::Label;
 use std::sync::Arc;
 use atrium_crypto::keypair::Secp256k1Keypair;
+use crate::auth::DidResolver;
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: DbPool,
     pub keypair: Arc<Secp256k1Keypair>,
     pub tx: tokio::sync::broadcast::Sender<(i64, Vec<Label>)>,
+    /// Flipped to `true` when the server is shutting down so open streams can close cleanly.
+    pub shutdown: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Resolves the signing keys of service auth token issuers.
+    pub resolver: Arc<dyn DidResolver>,
 }
@@ -0,0 +1,271 @@
+//! The poller and the daily batch driven end to end against an in-memory Bluesky account.
+#[cfg(test)]
+mod tests {
+    use crate::bsky::fake::{xrpc_error, FakeBsky};
+    use crate::bsky::Follower;
+    use crate::config::init_test_config;
+    use crate::db::{add_opt_out, get_labels, get_poller_state, init_db, DbPool};
+    use crate::domain::fortune::get_daily_fortune;
+    use crate::domain::labeling::assign_fortune;
+    use crate::metrics::metrics;
+    use crate::poller::Poller;
+    use crate::scheduler::{run_migration, run_optimized_batch};
+    use atrium_api::com::atproto::label::defs::Label;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use rand::rngs::OsRng;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    struct Harness {
+        fake: Arc<FakeBsky>,
+        pool: DbPool,
+        keypair: Arc<Secp256k1Keypair>,
+        tx: broadcast::Sender<(i64, Vec<Label>)>,
+    }
+
+    async fn setup() -> Harness {
+        // Labeling reads the global config
+        init_test_config();
+        Harness {
+            fake: Arc::new(FakeBsky::default()),
+            pool: init_db(":memory:").await.unwrap(),
+            keypair: Arc::new(Secp256k1Keypair::create(&mut OsRng)),
+            tx: broadcast::channel(1000).0,
+        }
+    }
+
+    impl Harness {
+        async fn poller(&self) -> Poller {
+            Poller::new(self.fake.clone(), self.pool.clone(), self.keypair.clone(), self.tx.clone()).await.unwrap()
+        }
+
+        /// The current fortune of `did`, if it has one.
+        async fn fortune(&self, did: &str) -> Option<String> {
+            get_labels(&self.pool, did, None, None).await.unwrap()
+                .into_iter()
+                .find(|l| l.neg == 0)
+                .map(|l| l.val)
+        }
+
+        /// Seq of the frame that labeled `did`.
+        async fn labeled_at(&self, did: &str) -> i64 {
+            sqlx::query_scalar("SELECT MAX(seq) FROM label_events WHERE uri = ?")
+                .bind(did)
+                .fetch_one(&self.pool)
+                .await
+                .unwrap()
+        }
+    }
+
+    fn timestamp(minute: usize) -> String {
+        format!("2026-01-01T{:02}:{:02}:00.000Z", minute / 60, minute % 60)
+    }
+
+    #[tokio::test]
+    async fn test_poller_labels_followers_and_likers_oldest_first() {
+        let h = setup().await;
+        h.fake.notify("follow", "did:plc:alice", &timestamp(1));
+        h.fake.notify("reply", "did:plc:bob", &timestamp(2));
+        h.fake.notify("like", "did:plc:carol", &timestamp(3));
+
+        let mut poller = h.poller().await;
+        assert_eq!(poller.step().await, Duration::ZERO, "logs in first");
+        assert_eq!(poller.step().await, Duration::from_secs(10));
+
+        let alice = get_daily_fortune("did:plc:alice").to_string();
+        assert_eq!(h.fortune("did:plc:alice").await, Some(alice));
+        assert!(h.fortune("did:plc:carol").await.is_some());
+        assert_eq!(h.fortune("did:plc:bob").await, None);
+        assert!(h.labeled_at("did:plc:alice").await < h.labeled_at("did:plc:carol").await);
+
+        assert_eq!(h.fake.state.lock().unwrap().seen_at, Some(timestamp(3)));
+        assert_eq!(get_poller_state(&h.pool, "last_seen_at").await.unwrap(), Some(timestamp(3)));
+
+        // Nothing new, nothing relabeled
+        let before = h.labeled_at("did:plc:carol").await;
+        poller.step().await;
+        assert_eq!(h.labeled_at("did:plc:carol").await, before);
+    }
+
+    #[tokio::test]
+    async fn test_poller_catches_up_after_restart() {
+        let h = setup().await;
+        h.fake.notify("follow", "did:plc:first", &timestamp(0));
+        let mut poller = h.poller().await;
+        poller.step().await;
+        poller.step().await;
+        assert_eq!(poller.last_seen_at(), Some(timestamp(0).as_str()));
+        drop(poller);
+
+        // More than two pages arrive while the service is down
+        for i in 1..=120 {
+            h.fake.notify("follow", &format!("did:plc:user{}", i), &timestamp(i));
+        }
+
+        let mut poller = h.poller().await;
+        assert_eq!(poller.last_seen_at(), Some(timestamp(0).as_str()), "mark survives the restart");
+        poller.step().await;
+        poller.step().await;
+        for i in 1..=120 {
+            assert!(h.fortune(&format!("did:plc:user{}", i)).await.is_some(), "user{} labeled", i);
+        }
+        assert!(h.labeled_at("did:plc:user1").await < h.labeled_at("did:plc:user120").await);
+        assert_eq!(poller.last_seen_at(), Some(timestamp(120).as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_poller_pages_past_the_cap_without_skipping() {
+        let h = setup().await;
+        h.fake.notify("follow", "did:plc:first", &timestamp(0));
+        let mut poller = h.poller().await;
+        poller.step().await;
+        poller.step().await;
+
+        // More than the 10 pages of 50 a regular poll reads, with the oldest follow beyond them
+        let follows: Vec<usize> = (1..=600).filter(|i| *i == 1 || i % 100 == 0).collect();
+        for i in 1..=600 {
+            let reason = if follows.contains(&i) { "follow" } else { "reply" };
+            h.fake.notify(reason, &format!("did:plc:user{}", i), &timestamp(i));
+        }
+
+        // The first pass stops at the cap without labeling anything or moving the mark
+        assert_eq!(poller.step().await, Duration::ZERO);
+        assert_eq!(h.fortune("did:plc:user600").await, None);
+        assert_eq!(poller.last_seen_at(), Some(timestamp(0).as_str()));
+        assert_eq!(get_poller_state(&h.pool, "last_seen_at").await.unwrap(), Some(timestamp(0)));
+
+        assert_eq!(poller.step().await, Duration::from_secs(10));
+        for i in follows {
+            assert!(h.fortune(&format!("did:plc:user{}", i)).await.is_some(), "user{} labeled", i);
+        }
+        assert!(h.labeled_at("did:plc:user1").await < h.labeled_at("did:plc:user600").await);
+        assert_eq!(poller.last_seen_at(), Some(timestamp(600).as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_poller_skips_notifications_it_cannot_label() {
+        let h = setup().await;
+        sqlx::query("CREATE TRIGGER broken BEFORE INSERT ON label_events WHEN NEW.uri = 'did:plc:broken' BEGIN SELECT RAISE(ABORT, 'broken'); END")
+            .execute(&h.pool)
+            .await
+            .unwrap();
+        h.fake.notify("follow", "did:plc:alice", &timestamp(1));
+        h.fake.notify("follow", "did:plc:broken", &timestamp(2));
+        h.fake.notify("like", "did:plc:carol", &timestamp(3));
+        let skipped = metrics().poller_skipped.get();
+
+        let mut poller = h.poller().await;
+        poller.step().await;
+        assert_eq!(poller.step().await, Duration::from_secs(10), "no backoff");
+        assert!(h.fortune("did:plc:alice").await.is_some());
+        assert_eq!(h.fortune("did:plc:broken").await, None);
+        assert!(h.fortune("did:plc:carol").await.is_some(), "later notifications aren't held up");
+        assert!(metrics().poller_skipped.get() > skipped);
+        assert_eq!(poller.last_seen_at(), Some(timestamp(3).as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_poller_retries_notifications_while_the_database_is_busy() {
+        use sqlx::Connection;
+
+        // Locking needs a database file that several connections share
+        let path = std::env::temp_dir().join(format!("omikuji-busy-{}.db", rand::random::<u64>()));
+        let path = path.to_str().unwrap().to_string();
+        let mut h = setup().await;
+        h.pool = init_db(&path).await.unwrap();
+        // Give up on a lock quickly instead of after the default five seconds
+        let mut conns = Vec::new();
+        for _ in 0..5 {
+            let mut conn = h.pool.acquire().await.unwrap();
+            sqlx::query("PRAGMA busy_timeout = 100").execute(&mut *conn).await.unwrap();
+            conns.push(conn);
+        }
+        drop(conns);
+
+        h.fake.notify("follow", "did:plc:alice", &timestamp(1));
+        let mut poller = h.poller().await;
+        poller.step().await;
+
+        let mut locker = sqlx::SqliteConnection::connect(&format!("sqlite:{}", path)).await.unwrap();
+        sqlx::query("BEGIN EXCLUSIVE").execute(&mut locker).await.unwrap();
+        assert!(poller.step().await >= Duration::from_secs(5), "backs off");
+        assert_eq!(poller.last_seen_at(), None, "not skipped");
+
+        sqlx::query("ROLLBACK").execute(&mut locker).await.unwrap();
+        assert_eq!(poller.step().await, Duration::from_secs(10));
+        assert!(h.fortune("did:plc:alice").await.is_some());
+        assert_eq!(poller.last_seen_at(), Some(timestamp(1).as_str()));
+
+        h.pool.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_poller_backs_off_and_logs_in_again() {
+        let h = setup().await;
+        let mut poller = h.poller().await;
+        assert_eq!(poller.step().await, Duration::ZERO);
+
+        // A server error is retried after a backoff, without logging in again
+        h.fake.state.lock().unwrap().failures.push_back(
+            xrpc_error::<atrium_api::app::bsky::notification::list_notifications::Error>(502, "UpstreamFailure").into()
+        );
+        let delay = poller.step().await;
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10), "{:?}", delay);
+        assert_eq!(h.fake.state.lock().unwrap().logins, 1);
+
+        // An expired session leads to a new login, then polling resumes
+        h.fake.expire_session();
+        h.fake.notify("follow", "did:plc:alice", &timestamp(1));
+        let delay = poller.step().await;
+        assert!(delay >= Duration::from_secs(10), "backoff grows: {:?}", delay);
+        assert_eq!(poller.step().await, Duration::ZERO);
+        assert_eq!(h.fake.state.lock().unwrap().logins, 2);
+        assert_eq!(poller.step().await, Duration::from_secs(10));
+        assert!(h.fortune("did:plc:alice").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_batch_labels_followers_and_revokes_the_rest() {
+        let h = setup().await;
+        {
+            let mut state = h.fake.state.lock().unwrap();
+            for did in ["did:plc:alice", "did:plc:bob", "did:plc:quiet"] {
+                state.followers.push(Follower { did: did.to_string(), handle: format!("{}.test", &did[8..]) });
+            }
+        }
+        add_opt_out(&mut h.pool.acquire().await.unwrap(), "did:plc:quiet", "report").await.unwrap();
+        assign_fortune("did:plc:gone", None, &h.pool, &h.keypair, "did:plc:test", &h.tx).await.unwrap();
+        assert!(h.fortune("did:plc:gone").await.is_some());
+
+        run_optimized_batch(h.fake.clone(), h.pool.clone(), h.keypair.clone(), h.tx.clone()).await.unwrap();
+
+        assert_eq!(h.fake.state.lock().unwrap().logins, 1);
+        assert_eq!(h.fortune("did:plc:alice").await, Some(get_daily_fortune("did:plc:alice").to_string()));
+        assert_eq!(h.fortune("did:plc:bob").await, Some(get_daily_fortune("did:plc:bob").to_string()));
+        assert_eq!(h.fortune("did:plc:quiet").await, None, "opted out");
+        assert_eq!(h.fortune("did:plc:gone").await, None, "no longer follows");
+    }
+
+    #[tokio::test]
+    async fn test_migration_runs_once() {
+        let h = setup().await;
+        assign_fortune("did:plc:alice", None, &h.pool, &h.keypair, "did:plc:test", &h.tx).await.unwrap();
+        // The migration waits for a subscriber before broadcasting
+        let _rx = h.tx.subscribe();
+        let events = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM label_events").fetch_one(&h.pool).await.unwrap()
+        };
+
+        let before = events().await;
+        run_migration(h.pool.clone(), h.keypair.clone(), h.tx.clone()).await.unwrap();
+        let after = events().await;
+        assert!(after > before);
+        assert!(h.fortune("did:plc:alice").await.is_some());
+        assert!(get_poller_state(&h.pool, "id_rotation_migrated_at").await.unwrap().is_some());
+
+        run_migration(h.pool.clone(), h.keypair.clone(), h.tx.clone()).await.unwrap();
+        assert_eq!(events().await, after, "nothing appended on the next start");
+    }
+}